@@ -4,11 +4,17 @@ use spinners::{Spinner, Spinners};
 use std::{
     env::args,
     io::{self, Write},
+    sync::Arc,
 };
 
 use utils::{
     chat_agent::ChatAgent,
-    vector_space::{process_data, BookDataLoader, MovieDataLoder},
+    classifier_eval::{evaluate, load_jsonl, LabeledQuery, MockClassifier, TopicScorer},
+    config_praser::Config,
+    llm_server::ApiServerState,
+    rag_eval::{EvalQuestion, RagEvaluator},
+    store::{open_store, VectorStore},
+    vector_space::{data_loader, process_data},
 };
 
 use tokio::time::{sleep as Tsleep, Duration as TDuration};
@@ -17,72 +23,139 @@ use tokio::time::{sleep as Tsleep, Duration as TDuration};
 const LLM_SERVER_URL: &str = "http://127.0.0.1:3000/v1";
 const CLASSIFIER_URL: &str = "http://127.0.0.1:3000/v1/classifier";
 
-// load data and create embeddings
-async fn load_data(
-    movie_json_file_path: String,
-    book_json_file_path: String,
-    number_of_movie_data: Option<u32>,
-    number_of_book_data: Option<u32>,
-    db_url: &str,
-    embedder_url: &str,
-    model_name: &str,
+// load data and create embeddings of every domain
+async fn load_data(config: &Config, store: Arc<dyn VectorStore>) {
+    for domain in config.domains.iter() {
+        let data_loader = data_loader(domain).unwrap();
+        process_data(
+            domain,
+            &config.servers.model_name,
+            store.clone(),
+            &config.embedding,
+            data_loader.as_ref(),
+            &config.servers.ollama_api_server_url,
+        )
+        .await;
+    }
+}
+
+// run a labeled jsonl file of queries through the topic classifier and print its accuracy
+async fn classify_eval(
+    config: &Config,
+    store: Arc<dyn VectorStore>,
+    queries_path: &str,
+    mock: bool,
 ) {
-    process_data(
-        movie_json_file_path,
-        model_name,
-        db_url.to_string(),
-        "books_collection".to_string(),
-        true,
-        BookDataLoader,
-        number_of_book_data,
-        embedder_url,
-    )
-    .await;
+    let queries = load_jsonl::<LabeledQuery>(queries_path).unwrap();
 
-    process_data(
-        book_json_file_path,
-        model_name,
-        db_url.to_string(),
-        "movies_collection".to_string(),
-        true,
-        MovieDataLoder,
-        number_of_movie_data,
-        embedder_url,
+    let mock_classifier = MockClassifier::new(&config.domains);
+    let mut chatagent = ChatAgent::from_config(
+        config,
+        store,
+        LLM_SERVER_URL.to_string(),
+        CLASSIFIER_URL.to_string(),
+    );
+    let topic_classifier;
+    let scorer: &(dyn TopicScorer + Sync) = if mock {
+        &mock_classifier
+    } else {
+        topic_classifier = chatagent.topic_classifier().await;
+        topic_classifier.as_ref()
+    };
+
+    let report = evaluate(
+        scorer,
+        &config.domains,
+        &queries,
+        config.classifier.threshold,
     )
     .await;
+    println!("{}", report);
+}
+
+// answer a jsonl file of questions with the chat agent and write the quality report
+async fn rag_eval(config: &Config, store: Arc<dyn VectorStore>, questions_path: &str) {
+    let questions = load_jsonl::<EvalQuestion>(questions_path).unwrap();
+
+    let mut chatagent = ChatAgent::from_config(
+        config,
+        store,
+        LLM_SERVER_URL.to_string(),
+        CLASSIFIER_URL.to_string(),
+    );
+    let evaluator = RagEvaluator::new(config, LLM_SERVER_URL.to_string());
+    let report = evaluator.evaluate(&mut chatagent, &questions).await;
+
+    let summary = &report.summary;
+    println!(
+        "{} questions: recall@{} {:.3}, mrr {:.3}, faithfulness {:.3}, relevance {:.3}",
+        summary.questions,
+        summary.k,
+        summary.recall_at_k,
+        summary.mrr,
+        summary.faithfulness,
+        summary.relevance
+    );
+    report.save(&config.eval.report_path).unwrap();
+    println!("report written to {}", config.eval.report_path);
 }
 
 #[tokio::main]
 async fn main() {
+    let arg = args().collect::<Vec<String>>();
+    assert!(
+        arg.len() >= 2,
+        "{}",
+        "config.toml file path required.".red().bold()
+    );
+
+    let config = utils::config_praser::load_config(arg.get(1).unwrap().to_string()).unwrap();
+    let store = open_store(&config).unwrap();
+
+    // the classifier examples are embedded once for the api server and the chat loop
+    let topic_classifier = ChatAgent::from_config(
+        &config,
+        store.clone(),
+        LLM_SERVER_URL.to_string(),
+        CLASSIFIER_URL.to_string(),
+    )
+    .topic_classifier()
+    .await;
+
     // run llm api server in newly Spawns asynchronous task
+    let server_state = ApiServerState {
+        config: Arc::new(config.clone()),
+        store: store.clone(),
+        topic_classifier: topic_classifier.clone(),
+        llm_server_url: LLM_SERVER_URL.to_string(),
+        classifier_url: CLASSIFIER_URL.to_string(),
+    };
     tokio::spawn(async move {
-        utils::llm_server::llm_apiserver().await;
+        utils::llm_server::llm_apiserver(server_state).await;
     });
 
     Tsleep(TDuration::from_secs(3)).await;
 
-    let arg = args().collect::<Vec<String>>();
-    assert_eq!(
-        arg.len(),
-        2,
-        "{}",
-        "config.toml file path required.".red().bold()
-    );
+    // chatbot-app config.toml classify-eval queries.jsonl [--mock]
+    if arg.get(2).map(|a| a.as_str()) == Some("classify-eval") {
+        let queries_path = arg
+            .get(3)
+            .expect("labeled queries jsonl file path required.");
+        let mock = arg.iter().any(|a| a == "--mock");
+        classify_eval(&config, store, queries_path, mock).await;
+        return;
+    }
 
-    let config = utils::config_praser::load_config(arg.get(1).unwrap().to_string()).unwrap();
+    // chatbot-app config.toml eval questions.jsonl
+    if arg.get(2).map(|a| a.as_str()) == Some("eval") {
+        let questions_path = arg.get(3).expect("questions jsonl file path required.");
+        rag_eval(&config, store, questions_path).await;
+        return;
+    }
 
     let load = config.embedding.create_embedding;
     if load {
-        load_data(
-            config.embedding.movies_data_path,
-            config.embedding.books_data_path,
-            config.embedding.number_of_movies_data,
-            config.embedding.number_of_books_data,
-            &config.servers.vector_store_db_url,
-            &config.servers.ollama_api_server_url,
-            &config.servers.model_name,
-        )
-        .await;
+        load_data(&config, store.clone()).await;
     }
 
     println!(
@@ -90,14 +163,13 @@ async fn main() {
         "chatbot".red().bold()
     );
 
-    let mut chatagent = ChatAgent::new(
+    let mut chatagent = ChatAgent::from_config(
+        &config,
+        store,
         LLM_SERVER_URL.to_string(),
         CLASSIFIER_URL.to_string(),
-        config.servers.api_key.clone(),
-        config.servers.model_name.clone(),
-        config.servers.vector_store_db_url.clone(),
-        config.servers.ollama_api_server_url.clone(),
-    );
+    )
+    .with_topic_classifier(topic_classifier);
 
     loop {
         print!("==> 🧑 {}: ", "You".green().bold());
@@ -114,11 +186,16 @@ async fn main() {
         }
         let mut sp = Spinner::new(Spinners::Dots9, "".into());
 
-        let output = chatagent
-            .get_response(user_input.to_string())
-            .await
-            .to_string();
+        let output = chatagent.get_response(user_input.to_string()).await;
         sp.stop();
         println!("==> 🤖 {}: {}\n", "chatbot".red().bold(), output);
+
+        if !output.citations.is_empty() {
+            println!("{}", "Sources:".yellow().bold());
+            for citation in output.citations.iter() {
+                println!("    {}", citation);
+            }
+            println!();
+        }
     }
 }
@@ -1,22 +1,146 @@
-use langchain_rust::vectorstore::VectorStore;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::Arc,
+};
 
+use futures::future::join_all;
 use langchain_rust::{
-    language_models::llm::LLM,
+    embedding::Embedder,
+    language_models::{llm::LLM, LLMError},
     llm::{OpenAI, OpenAIConfig},
-    schemas::{Document, Message},
-    similarity_search,
-    vectorstore::pgvector::{Store, StoreBuilder},
+    schemas::{Document, Message, MessageType},
+};
+use reqwest::Client;
+use serde::Serialize;
+use serde_json::{json, Value};
+use thiserror::Error;
+
+use super::{
+    chunker::{parent_id, reassemble},
+    config_praser::{Classifier, Config, Domain, Guardrails, Rerank, Retrieval, RetrievalFallback},
+    guardrails::{GuardrailChain, Stage},
+    prompt::{render, DEFAULT_CHAT_PROMPT},
+    reranker::{Reranker, RerankerKind},
+    retriever::{max_marginal_relevance, parse_query_filters, SearchQuery},
+    store::VectorStore,
+    topic_clasifier::TopicClassifier,
+    vector_space::EmbeddingManager,
 };
 
-use super::{topic_clasifier::TopicClassifier, vector_space::EmbeddingManager};
+// retrieved document cited as `[index]` in the answer
+#[derive(Debug, Clone, Serialize)]
+pub struct Citation {
+    pub index: usize,
+    pub score: f64,
+    pub metadata: HashMap<String, Value>,
+}
+
+impl fmt::Display for Citation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let field = |key: &str| self.metadata.get(key).and_then(|v| v.as_str());
+
+        write!(
+            f,
+            "[{}] {}",
+            self.index,
+            field("title").unwrap_or("untitled")
+        )?;
+        if let Some(author) = field("author") {
+            write!(f, " by {}", author)?;
+        }
+        if let Some(date) = field("release_date").or(field("publication_date")) {
+            write!(f, " ({})", date)?;
+        }
+        write!(f, " [score {:.2}]", self.score)
+    }
+}
+
+// answer of the agent along with the documents retrieved to build it
+#[derive(Debug, Default)]
+pub struct AgentResponse {
+    pub answer: String,
+    pub citations: Vec<Citation>,
+    pub sources: Vec<Document>,
+}
+
+impl fmt::Display for AgentResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.answer)
+    }
+}
+
+// merge the ranked documents of several collections in one list. the rerank scores are
+// comparable across collections, otherwise the lists are interleaved by rank, the best
+// matching domain first
+fn merge_ranked(results: Vec<Vec<(Document, Option<f64>)>>) -> Vec<Document> {
+    let reranked = results
+        .iter()
+        .flatten()
+        .all(|(_, rerank_score)| rerank_score.is_some());
+    let mut ranked = results
+        .into_iter()
+        .flat_map(|docs| docs.into_iter().enumerate())
+        .collect::<Vec<(usize, (Document, Option<f64>))>>();
+    if reranked {
+        ranked.sort_by(|a, b| b.1 .1.unwrap().total_cmp(&a.1 .1.unwrap()));
+    } else {
+        ranked.sort_by_key(|(rank, _)| *rank);
+    }
+    ranked.into_iter().map(|(_, (doc, _))| doc).collect()
+}
+
+// collect the `[n]` / `[n, m]` markers of the answer that point to one of the documents
+fn parse_citations(answer: &str, docs: &[Document]) -> Vec<Citation> {
+    let mut indexes = Vec::<usize>::new();
+
+    for part in answer.split('[').skip(1) {
+        let Some((inner, _)) = part.split_once(']') else {
+            continue;
+        };
+        for num in inner.split(',') {
+            if let Ok(index) = num.trim().parse::<usize>() {
+                if index >= 1 && index <= docs.len() && !indexes.contains(&index) {
+                    indexes.push(index);
+                }
+            }
+        }
+    }
+
+    indexes
+        .into_iter()
+        .map(|index| Citation {
+            index,
+            score: docs[index - 1].score,
+            metadata: docs[index - 1].metadata.clone(),
+        })
+        .collect()
+}
+
+#[derive(Debug, Error)]
+pub enum ChatAgentError {
+    #[error("Chat model request failed: {0}")]
+    Llm(#[from] LLMError),
+    #[error("Raw prompt request failed: {0}")]
+    RawPrompt(#[from] reqwest::Error),
+}
 
 pub struct ChatAgent {
     llm: OpenAI<OpenAIConfig>,
+    api_base_url: String,
     classifier_url: String,
-    db_url: String,
+    store: Arc<dyn VectorStore>,
     model_name: String,
     embedder_url: String,
+    retrieval: Retrieval,
+    rerank: Rerank,
+    domains: Vec<Domain>,
+    classifier: Classifier,
+    topic_classifier: Option<Arc<TopicClassifier>>,
     memory: Vec<Message>,
+    raw_prompt: bool,
+    guardrails: GuardrailChain,
+    refusal_message: String,
 }
 
 impl ChatAgent {
@@ -25,93 +149,573 @@ impl ChatAgent {
         classifier_url: String,
         api_key: String,
         model_name: String,
-        db_url: String,
+        store: Arc<dyn VectorStore>,
         embedder_url: String,
     ) -> Self {
         let openconf = OpenAIConfig::new()
-            .with_api_base(api_base_url)
+            .with_api_base(api_base_url.clone())
             .with_api_key(api_key);
         let llm = OpenAI::new(openconf).with_model(model_name.clone());
 
         Self {
             llm,
+            api_base_url,
             classifier_url,
-            db_url,
+            store,
             model_name,
             embedder_url,
-            memory: vec![Message::new_system_message("
-            ### System:
-            System: You are a friendly consice assistant that answer the user query using the following pieces of 
-            retrieved context to answer the query. If you don't know the answer, or are unsure, say you don't know.
-            ")]
+            retrieval: Retrieval::default(),
+            rerank: Rerank::default(),
+            domains: Vec::new(),
+            classifier: Classifier::default(),
+            topic_classifier: None,
+            memory: vec![Message::new_system_message(DEFAULT_CHAT_PROMPT)],
+            raw_prompt: false,
+            guardrails: GuardrailChain::default(),
+            refusal_message: String::default(),
         }
     }
 
-    pub async fn get_response(&mut self, query: String) -> String {
-        let topic_clasifier = TopicClassifier::new(self.classifier_url.clone());
+    pub fn from_config(
+        config: &Config,
+        store: Arc<dyn VectorStore>,
+        api_base_url: String,
+        classifier_url: String,
+    ) -> Self {
+        Self::new(
+            api_base_url,
+            classifier_url,
+            config.servers.api_key.clone(),
+            config.servers.model_name.clone(),
+            store,
+            config.servers.ollama_api_server_url.clone(),
+        )
+        .with_retrieval(config.retrieval.clone())
+        .with_rerank(config.rerank.clone())
+        .with_domains(config.domains.clone())
+        .with_classifier(config.classifier.clone())
+        .with_chat_prompt(&config.prompts.chat_template)
+        .with_raw_prompt(config.servers.raw_prompt)
+        .with_guardrails(&config.guardrails)
+    }
+
+    // checks run on the query, the retrieved documents and the answer
+    pub fn with_guardrails(mut self, guardrails: &Guardrails) -> Self {
+        self.guardrails = GuardrailChain::from_config(guardrails, self.llm.clone());
+        self.refusal_message = guardrails.refusal_message.clone();
+        self
+    }
+
+    // send the rag prompt as one templated text, for models without a chat template
+    pub fn with_raw_prompt(mut self, raw_prompt: bool) -> Self {
+        self.raw_prompt = raw_prompt;
+        self
+    }
+
+    // system prompt of the answers without retrieved context
+    pub fn with_chat_prompt(mut self, prompt: &str) -> Self {
+        self.memory[0] = Message::new_system_message(prompt);
+        self
+    }
+
+    pub fn with_retrieval(mut self, retrieval: Retrieval) -> Self {
+        self.retrieval = retrieval;
+        self
+    }
+
+    pub fn with_rerank(mut self, rerank: Rerank) -> Self {
+        self.rerank = rerank;
+        self
+    }
+
+    // domains the queries are routed to, each one searched in its own collection
+    pub fn with_domains(mut self, domains: Vec<Domain>) -> Self {
+        self.domains = domains;
+        self.topic_classifier = None;
+        self
+    }
+
+    pub fn with_classifier(mut self, classifier: Classifier) -> Self {
+        self.classifier = classifier;
+        self.topic_classifier = None;
+        self
+    }
 
-        let topic = topic_clasifier.classify(query.clone()).await.unwrap();
+    // seed the conversation memory with previous turns, e.g. from an api request
+    pub fn with_history(mut self, history: Vec<Message>) -> Self {
+        self.memory.extend(history);
+        self
+    }
+
+    // forget the previous turns, only the system message is kept
+    pub fn clear_history(&mut self) {
+        self.memory.truncate(1);
+    }
+
+    // classifier of the domains, the embedding classifier examples are embedded on first use
+    // classifier built once and shared by the agents, e.g. the ones of the api requests
+    pub fn with_topic_classifier(mut self, topic_classifier: Arc<TopicClassifier>) -> Self {
+        self.topic_classifier = Some(topic_classifier);
+        self
+    }
 
-        let mut ctopic = "other".to_string();
-        if topic.to_lowercase().contains("movie") {
-            ctopic = "movie".to_string();
-        } else if topic.to_lowercase().contains("book") {
-            ctopic = "book".to_string();
+    pub async fn topic_classifier(&mut self) -> Arc<TopicClassifier> {
+        if self.topic_classifier.is_none() {
+            let mut topic_clasifier =
+                TopicClassifier::new(self.classifier_url.clone(), &self.domains)
+                    .with_config(self.classifier.clone())
+                    .with_embedder(self.model_name.clone(), self.embedder_url.clone())
+                    .with_llm(self.llm.clone());
+            if let Err(e) = topic_clasifier.load_centroids().await {
+                println!("Error loading the classifier examples: {}", e);
+            }
+            self.topic_classifier = Some(Arc::new(topic_clasifier));
         }
+        self.topic_classifier.clone().unwrap()
+    }
 
-        let mut _docs = Vec::<Document>::new();
+    pub async fn get_response(&mut self, query: String) -> AgentResponse {
+        let query = match self.guardrails.check(Stage::Input, &query).await {
+            Ok(query) => query,
+            Err(_) => return self.refusal(),
+        };
 
-        if ctopic == "book" || ctopic == "movie" {
-            let embedding_manager =
-                EmbeddingManager::new(&self.model_name, self.embedder_url.clone());
+        // only the checked answer goes in the history, a refused or failed turn is forgotten
+        let mut response = match self.answer(query.clone()).await {
+            Ok(response) => response,
+            Err(e) => {
+                println!("Error answering the query: {}", e);
+                return AgentResponse {
+                    answer: "Sorry, I cannot answer right now, please try again later.".to_string(),
+                    ..Default::default()
+                };
+            }
+        };
+        match self.guardrails.check(Stage::Output, &response.answer).await {
+            Ok(answer) => response.answer = answer,
+            Err(_) => return self.refusal(),
+        }
+        self.memory.push(Message::new_human_message(query));
+        self.memory
+            .push(Message::new_ai_message(response.answer.clone()));
+        response
+    }
+
+    fn refusal(&self) -> AgentResponse {
+        AgentResponse {
+            answer: self.refusal_message.clone(),
+            ..Default::default()
+        }
+    }
+
+    async fn answer(&mut self, query: String) -> Result<AgentResponse, ChatAgentError> {
+        let topics = match self.topic_classifier().await.classify(query.clone()).await {
+            Ok(topics) => topics,
+            Err(e) => {
+                println!("Error classifying the query: {}", e);
+                Vec::new()
+            }
+        };
 
-            let col_name = if ctopic == "book" {
-                "books_collection"
-            } else {
-                "movies_collection"
-            };
-            let store: Store = StoreBuilder::new()
-                .embedder(embedding_manager.get_embeddings())
-                .collection_name(&col_name)
-                .connection_url(&self.db_url)
-                .vector_dimensions(2048)
-                .build()
+        let mut domains = Vec::<Domain>::new();
+        for (topic, _) in topics.iter() {
+            let domain = self
+                .domains
+                .iter()
+                .find(|d| d.label.eq_ignore_ascii_case(topic));
+            if let Some(domain) = domain {
+                if !domains.iter().any(|d| d.label == domain.label) {
+                    domains.push(domain.clone());
+                }
+            }
+        }
+
+        if domains.is_empty() {
+            return self.chat_answer(query).await;
+        }
+
+        // search the collections in parallel, the documents are labeled with their source type
+        let results = join_all(
+            domains
+                .iter()
+                .map(|domain| self.retrieve(&domain.collection, &query)),
+        )
+        .await;
+
+        let results = domains
+            .iter()
+            .zip(results)
+            .map(|(domain, col_docs)| {
+                col_docs
+                    .into_iter()
+                    .map(|(mut d, rerank_score)| {
+                        d.metadata
+                            .insert("source_type".to_string(), Value::from(domain.label.clone()));
+                        (d, rerank_score)
+                    })
+                    .collect()
+            })
+            .collect();
+        let docs = self.check_documents(merge_ranked(results)).await;
+
+        if !docs.is_empty() {
+            // the best matching domain gives the prompt
+            return self.rag_answer(&query, docs, &domains[0]).await;
+        }
+
+        match self.retrieval.fallback {
+            RetrievalFallback::Chat => self.chat_answer(query).await,
+            RetrievalFallback::NotInCatalog => Ok(AgentResponse {
+                answer: format!(
+                    "Sorry, I couldn't find anything about that in the {} catalog.",
+                    domains
+                        .iter()
+                        .map(|d| d.label.as_str())
+                        .collect::<Vec<&str>>()
+                        .join(" and ")
+                ),
+                ..Default::default()
+            }),
+        }
+    }
+
+    // documents refused by the context checks, e.g. a description hiding instructions, are
+    // not used as context
+    async fn check_documents(&self, docs: Vec<Document>) -> Vec<Document> {
+        let mut checked = Vec::<Document>::new();
+        for mut doc in docs {
+            match self
+                .guardrails
+                .check(Stage::Context, &doc.page_content)
                 .await
-                .unwrap();
+            {
+                Ok(content) => {
+                    doc.page_content = content;
+                    checked.push(doc);
+                }
+                Err(reason) => println!(
+                    "Dropping document {:?}: {}",
+                    doc.metadata.get("title"),
+                    reason
+                ),
+            }
+        }
+        checked
+    }
 
-            _docs = similarity_search!(store, &topic, 5).await.unwrap();
-            if _docs.len() == 0 {
-                return "Sorry unable to resolve your query.".to_string();
+    // similarity search in the collection, keeping only the documents above the score threshold.
+    // metadata filters come from the config and from `field:value` tokens of the query. the
+    // documents are returned in their ranking order with their rerank score
+    async fn retrieve(&self, col_name: &str, query: &str) -> Vec<(Document, Option<f64>)> {
+        let (query, mut filters) = parse_query_filters(query);
+        filters.extend(self.retrieval.filters.iter().cloned());
+
+        let embedding_manager = EmbeddingManager::new(&self.model_name, self.embedder_url.clone());
+        let query_vector = match embedding_manager.get_embeddings().embed_query(&query).await {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error embedding query: {:?}", e);
+                return Vec::new();
+            }
+        };
+
+        let k = self.retrieval.k;
+        let fetch_k = if self.rerank.enabled {
+            self.rerank.candidates.max(k)
+        } else if self.retrieval.mmr {
+            self.retrieval.mmr_fetch_k.max(k)
+        } else {
+            k
+        };
+
+        let search_query = SearchQuery {
+            text: &query,
+            vector: &query_vector,
+            filters: &filters,
+            aliases: &self.retrieval.field_aliases,
+        };
+        // a query embedded by another model would silently match nothing
+        if let Ok(Some(dimensions)) = self.store.collection_dimensions(col_name).await {
+            if dimensions != query_vector.len() {
+                println!(
+                    "Collection {} has {} dimensions but the query embedding has {}, \
+                    create the embeddings again with the current model",
+                    col_name,
+                    dimensions,
+                    query_vector.len()
+                );
+                return Vec::new();
+            }
+        }
+        let candidates = self
+            .store
+            .search(
+                self.retrieval.search_mode_for(col_name),
+                col_name,
+                &search_query,
+                fetch_k,
+                self.retrieval.fusion_weights(),
+            )
+            .await;
+        let mut candidates = match candidates {
+            Ok(c) => c,
+            Err(e) => {
+                println!("Error searching {}: {}", col_name, e);
+                return Vec::new();
             }
+        };
+
+        let hits = if self.rerank.enabled {
+            let reranker = self.reranker();
+            match reranker.rerank(&query, candidates.clone(), k).await {
+                Ok(hits) => hits,
+                Err(e) => {
+                    println!("Error reranking, using the search order: {}", e);
+                    candidates.truncate(k);
+                    candidates
+                }
+            }
+        } else if self.retrieval.mmr {
+            max_marginal_relevance(&query_vector, candidates, k, self.retrieval.mmr_lambda)
+        } else {
+            candidates.truncate(k);
+            candidates
+        };
+
+        // the threshold applies to the rerank score when the candidates were reranked. a
+        // document with every term of the query (exact title, actor name...) is kept even with
+        // a low similarity, matching a single term is not enough
+        let docs = hits
+            .into_iter()
+            .filter(|hit| {
+                let score = hit.rerank_score.unwrap_or(hit.document.score);
+                hit.lexical_match || score >= self.retrieval.score_threshold
+            })
+            .map(|hit| (hit.document, hit.rerank_score))
+            .collect::<Vec<(Document, Option<f64>)>>();
+
+        if self.retrieval.reassemble_chunks {
+            self.reassemble_chunks(col_name, docs).await
+        } else {
+            docs
+        }
+    }
 
-            let mut prompt_user = "### System: You are a friendly consice assistant that answer the user query 
-            using the following pieces of retrieved context to answer the query. If you don't know the answer, or are unsure, 
-            say you don't know.\n\n".to_string();
-            for d in _docs.iter() {
-                let pc = d.page_content.to_string();
-                prompt_user.push_str(&pc);
-                prompt_user.push_str("\n\n");
+    // the retrieved chunks replaced by their whole record, the chunks are kept when their
+    // siblings cannot be fetched
+    async fn reassemble_chunks(
+        &self,
+        col_name: &str,
+        ranked: Vec<(Document, Option<f64>)>,
+    ) -> Vec<(Document, Option<f64>)> {
+        let mut parent_ids = ranked
+            .iter()
+            .filter_map(|(doc, _)| parent_id(doc))
+            .collect::<Vec<String>>();
+        if parent_ids.is_empty() {
+            return ranked;
+        }
+        parent_ids.sort();
+        parent_ids.dedup();
+
+        match self.store.chunks_of(col_name, &parent_ids).await {
+            Ok(chunks) => {
+                // a record takes the place and the rerank score of its best ranked chunk
+                let mut seen = HashSet::<String>::new();
+                let (docs, scores): (Vec<Document>, Vec<Option<f64>>) = ranked.into_iter().unzip();
+                let scores = docs
+                    .iter()
+                    .zip(scores)
+                    .filter(|(doc, _)| parent_id(doc).is_none_or(|id| seen.insert(id)))
+                    .map(|(_, score)| score)
+                    .collect::<Vec<Option<f64>>>();
+                reassemble(docs, chunks).into_iter().zip(scores).collect()
             }
-            prompt_user.push_str("### User:");
-            prompt_user.push_str(&query);
-            prompt_user.push_str("### Assistant:");
+            Err(e) => {
+                println!("Error fetching the chunks of {}: {}", col_name, e);
+                ranked
+            }
+        }
+    }
+
+    fn reranker(&self) -> Reranker {
+        match self.rerank.kind {
+            RerankerKind::Llm => Reranker::Llm {
+                llm: Box::new(self.llm.clone()),
+                concurrency: self.rerank.concurrency,
+            },
+            RerankerKind::Endpoint => Reranker::Endpoint {
+                url: self.rerank.endpoint_url.clone(),
+            },
+        }
+    }
 
-            let output = self.llm.clone().invoke(&prompt_user).await.unwrap();
+    // the context goes in the system message and the query follows the previous turns, unless
+    // the model needs the raw templated prompt
+    async fn rag_answer(
+        &self,
+        query: &str,
+        docs: Vec<Document>,
+        domain: &Domain,
+    ) -> Result<AgentResponse, ChatAgentError> {
+        let context = docs
+            .iter()
+            .enumerate()
+            .map(|(i, d)| {
+                let source_type = d
+                    .metadata
+                    .get("source_type")
+                    .and_then(|v| v.as_str())
+                    .unwrap_or("document");
+                format!("[{}] ({}) {}", i + 1, source_type, d.page_content)
+            })
+            .collect::<Vec<String>>()
+            .join("\n\n");
 
-            output
+        let output = if self.raw_prompt {
+            let history = self.history();
+            let prompt_user = render(
+                &domain.prompt_template,
+                &[
+                    ("context", &context),
+                    ("query", query),
+                    ("history", &history),
+                ],
+            )
+            .expect("prompt templates are validated by load_config");
+            self.invoke_raw(&prompt_user).await?
         } else {
-            self.memory.push(Message::new_human_message(query));
-            let response = self
-                .llm
+            let system_prompt = render(
+                &domain.system_prompt,
+                &[("context", &context), ("query", query)],
+            )
+            .expect("prompt templates are validated by load_config");
+            let mut messages = vec![Message::new_system_message(system_prompt)];
+            messages.extend(self.memory.iter().skip(1).cloned());
+            messages.push(Message::new_human_message(query));
+            self.llm
                 .clone()
-                .generate(&self.memory)
+                .generate(&messages)
                 .await
-                .map(|res| res.generation)
-                .unwrap();
+                .map(|res| res.generation)?
+        };
 
-            self.memory.push(Message::new_ai_message(response.clone()));
-            response
-        }
-        // "check".to_string()
+        Ok(AgentResponse {
+            citations: parse_citations(&output, &docs),
+            answer: output,
+            sources: docs,
+        })
+    }
+
+    // previous turns of the conversation for the {history} placeholder of the raw prompt
+    fn history(&self) -> String {
+        self.memory
+            .iter()
+            .skip(1)
+            .map(|m| {
+                let role = match m.message_type {
+                    MessageType::AIMessage => "Assistant",
+                    _ => "User",
+                };
+                format!("{}: {}\n", role, m.content)
+            })
+            .collect()
+    }
+
+    // send the raw prompt as one message flagged `raw_prompt`, the api server then passes it to
+    // the model without its chat template
+    async fn invoke_raw(&self, prompt: &str) -> Result<String, reqwest::Error> {
+        let response: Value = Client::new()
+            .post(format!("{}/chat/completions", self.api_base_url))
+            .json(&json!({
+                "model": self.model_name,
+                "messages": [{"role": "user", "content": prompt}],
+                "raw_prompt": true
+            }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(response["choices"][0]["message"]["content"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string())
+    }
+
+    async fn chat_answer(&self, query: String) -> Result<AgentResponse, ChatAgentError> {
+        let mut messages = self.memory.clone();
+        messages.push(Message::new_human_message(query));
+        let response = self
+            .llm
+            .clone()
+            .generate(&messages)
+            .await
+            .map(|res| res.generation)?;
+
+        Ok(AgentResponse {
+            answer: response,
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranked(titles: &[(&str, f64)], rerank: bool) -> Vec<(Document, Option<f64>)> {
+        titles
+            .iter()
+            .map(|(title, score)| {
+                let mut doc = Document::new(*title);
+                doc.score = 1.0 - score;
+                (doc, rerank.then_some(*score))
+            })
+            .collect()
+    }
+
+    fn contents(docs: &[Document]) -> Vec<&str> {
+        docs.iter().map(|d| d.page_content.as_str()).collect()
+    }
+
+    #[test]
+    fn merge_interleaves_the_collections_by_rank() {
+        // the cosine scores are the reverse of the ranking order the search gave
+        let movies = ranked(&[("m1", 0.9), ("m2", 0.5)], false);
+        let books = ranked(&[("b1", 0.8), ("b2", 0.7), ("b3", 0.1)], false);
+        let docs = merge_ranked(vec![movies, books]);
+        assert_eq!(contents(&docs), vec!["m1", "b1", "m2", "b2", "b3"]);
+    }
+
+    #[test]
+    fn merge_orders_reranked_documents_by_rerank_score() {
+        let movies = ranked(&[("m1", 0.9), ("m2", 0.5)], true);
+        let books = ranked(&[("b1", 0.8), ("b2", 0.7), ("b3", 0.1)], true);
+        let docs = merge_ranked(vec![movies, books]);
+        assert_eq!(contents(&docs), vec!["m1", "b1", "b2", "m2", "b3"]);
+    }
+
+    #[test]
+    fn citations_keep_the_markers_pointing_to_a_document() {
+        let docs = ranked(&[("dune", 0.1), ("emma", 0.2), ("ulysses", 0.3)], false)
+            .into_iter()
+            .map(|(mut doc, _)| {
+                let title = Value::from(doc.page_content.clone());
+                doc.metadata.insert("title".to_string(), title);
+                doc
+            })
+            .collect::<Vec<Document>>();
+
+        // out of range, repeated, malformed and unclosed markers are skipped
+        let answer = "Read Emma [2] or Dune [1, 2]. See also [0], [4], [x], [3 and [3";
+        let citations = parse_citations(answer, &docs);
+        let indexes = citations.iter().map(|c| c.index).collect::<Vec<usize>>();
+        assert_eq!(indexes, vec![2, 1]);
+        assert_eq!(citations[0].score, docs[1].score);
+        assert_eq!(citations[0].metadata["title"], "emma");
+
+        assert!(parse_citations("no markers", &docs).is_empty());
+        assert!(parse_citations("[1]", &[]).is_empty());
     }
 }
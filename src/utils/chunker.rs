@@ -0,0 +1,303 @@
+use std::collections::HashMap;
+
+use langchain_rust::schemas::Document;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+use tiktoken_rs::{cl100k_base, CoreBPE};
+
+use super::config_praser::Chunking;
+
+// metadata of a chunk: id of the document it was cut from, its position among the chunks of
+// that document and its byte offset in the page content of the document
+pub const PARENT_ID_FIELD: &str = "_parent_id";
+pub const CHUNK_INDEX_FIELD: &str = "_chunk_index";
+pub const CHUNK_COUNT_FIELD: &str = "_chunk_count";
+pub const CHUNK_START_FIELD: &str = "_chunk_start";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChunkStrategy {
+    // one document per record
+    #[default]
+    None,
+    // split on paragraphs, then lines, sentences, words and characters until the pieces fit
+    Character,
+    // whole sentences, `size` in characters
+    Sentence,
+    // whole words, `size` in tokens of the cl100k tokenizer
+    Token,
+}
+
+// separators of the character strategy, from the largest to the smallest piece of text
+const SEPARATORS: [&str; 4] = ["\n\n", "\n", ". ", " "];
+
+pub struct Chunker {
+    strategy: ChunkStrategy,
+    size: usize,
+    overlap: usize,
+    bpe: Option<CoreBPE>,
+    sentence: Regex,
+    word: Regex,
+}
+
+impl Chunker {
+    pub fn new(config: &Chunking) -> Self {
+        let bpe = match config.strategy {
+            ChunkStrategy::Token => Some(cl100k_base().unwrap()),
+            _ => None,
+        };
+
+        Self {
+            strategy: config.strategy,
+            size: config.size.max(1),
+            overlap: config.overlap,
+            bpe,
+            sentence: Regex::new(r"(?s).+?(?:[.!?]+(?:\s+|$)|$)").unwrap(),
+            word: Regex::new(r"\S+\s*").unwrap(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.strategy != ChunkStrategy::None
+    }
+
+    // chunks of the text with their byte offset
+    pub fn split<'t>(&self, text: &'t str) -> Vec<(usize, &'t str)> {
+        let units = match self.strategy {
+            ChunkStrategy::None => return vec![(0, text)],
+            ChunkStrategy::Character => self.recursive_units(text, 0, &SEPARATORS),
+            ChunkStrategy::Sentence => self
+                .sentence
+                .find_iter(text)
+                .map(|m| (m.start(), m.as_str()))
+                .collect(),
+            ChunkStrategy::Token => self
+                .word
+                .find_iter(text)
+                .map(|m| (m.start(), m.as_str()))
+                .collect(),
+        };
+        self.merge(text, units)
+    }
+
+    // the chunks of a document, each one with the metadata of the document. a document that
+    // fits in one chunk is kept as it is
+    pub fn split_document(&self, doc: Document, parent_id: &str) -> Vec<Document> {
+        let chunks = self.split(&doc.page_content);
+        if chunks.len() <= 1 {
+            return vec![doc];
+        }
+
+        let count = chunks.len();
+        chunks
+            .into_iter()
+            .enumerate()
+            .map(|(index, (start, chunk))| {
+                let mut metadata = doc.metadata.clone();
+                metadata.insert(PARENT_ID_FIELD.to_string(), Value::from(parent_id));
+                metadata.insert(CHUNK_INDEX_FIELD.to_string(), Value::from(index));
+                metadata.insert(CHUNK_COUNT_FIELD.to_string(), Value::from(count));
+                metadata.insert(CHUNK_START_FIELD.to_string(), Value::from(start));
+                Document::new(chunk.to_string()).with_metadata(metadata)
+            })
+            .collect()
+    }
+
+    fn measure(&self, text: &str) -> usize {
+        match &self.bpe {
+            Some(bpe) => bpe.encode_ordinary(text).len(),
+            None => text.chars().count(),
+        }
+    }
+
+    // pieces of the text no larger than the chunk size, each one ending with its separator
+    fn recursive_units<'t>(
+        &self,
+        text: &'t str,
+        offset: usize,
+        separators: &[&str],
+    ) -> Vec<(usize, &'t str)> {
+        if self.measure(text) <= self.size {
+            return vec![(offset, text)];
+        }
+        let Some((separator, smaller)) = separators.split_first() else {
+            return text
+                .char_indices()
+                .map(|(i, c)| (offset + i, &text[i..i + c.len_utf8()]))
+                .collect();
+        };
+
+        let mut units = Vec::<(usize, &str)>::new();
+        let mut start = 0;
+        for (i, _) in text.match_indices(separator) {
+            let end = i + separator.len();
+            units.extend(self.recursive_units(&text[start..end], offset + start, smaller));
+            start = end;
+        }
+        if start < text.len() {
+            units.extend(self.recursive_units(&text[start..], offset + start, smaller));
+        }
+        units
+    }
+
+    // consecutive units grouped up to the chunk size. a chunk starts with the last units of the
+    // previous one, up to the overlap
+    fn merge<'t>(&self, text: &'t str, units: Vec<(usize, &'t str)>) -> Vec<(usize, &'t str)> {
+        let sizes = units
+            .iter()
+            .map(|(_, unit)| self.measure(unit))
+            .collect::<Vec<usize>>();
+
+        let mut chunks = Vec::<(usize, &str)>::new();
+        let mut first = 0;
+        while first < units.len() {
+            let mut last = first;
+            let mut size = sizes[first];
+            while last + 1 < units.len() && size + sizes[last + 1] <= self.size {
+                last += 1;
+                size += sizes[last];
+            }
+
+            let start = units[first].0;
+            let end = units[last].0 + units[last].1.len();
+            let chunk = text[start..end].trim_end();
+            if !chunk.is_empty() {
+                chunks.push((start, chunk));
+            }
+            if last + 1 >= units.len() {
+                break;
+            }
+
+            let mut next = last + 1;
+            let mut overlap = 0;
+            while next - 1 > first && overlap + sizes[next - 1] <= self.overlap {
+                next -= 1;
+                overlap += sizes[next];
+            }
+            first = next;
+        }
+        chunks
+    }
+}
+
+pub fn parent_id(doc: &Document) -> Option<String> {
+    doc.metadata
+        .get(PARENT_ID_FIELD)
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string())
+}
+
+fn chunk_field(doc: &Document, field: &str) -> usize {
+    doc.metadata
+        .get(field)
+        .and_then(|v| v.as_u64())
+        .unwrap_or_default() as usize
+}
+
+// the retrieved chunks replaced by their parent document, rebuilt from all the chunks of the
+// parent. a parent takes the rank of its first chunk and the best score of its chunks
+pub fn reassemble(docs: Vec<Document>, chunks: Vec<Document>) -> Vec<Document> {
+    let mut by_parent = HashMap::<String, Vec<Document>>::new();
+    for chunk in chunks {
+        if let Some(id) = parent_id(&chunk) {
+            by_parent.entry(id).or_default().push(chunk);
+        }
+    }
+
+    let mut parents = Vec::<Document>::new();
+    let mut positions = HashMap::<String, usize>::new();
+    for doc in docs {
+        let Some(id) = parent_id(&doc) else {
+            parents.push(doc);
+            continue;
+        };
+        if let Some(&position) = positions.get(&id) {
+            parents[position].score = parents[position].score.max(doc.score);
+            continue;
+        }
+        let siblings = by_parent.remove(&id).unwrap_or_default();
+        positions.insert(id, parents.len());
+        parents.push(merge_chunks(doc, siblings));
+    }
+    parents
+}
+
+// page content of the parent, the overlapping text of consecutive chunks is only kept once.
+// chunks of another version of the record, with another chunk count, are left out
+fn merge_chunks(retrieved: Document, chunks: Vec<Document>) -> Document {
+    let count = chunk_field(&retrieved, CHUNK_COUNT_FIELD);
+    let mut chunks = chunks
+        .into_iter()
+        .filter(|c| {
+            chunk_field(c, CHUNK_COUNT_FIELD) == count && chunk_field(c, CHUNK_INDEX_FIELD) < count
+        })
+        .collect::<Vec<Document>>();
+    if chunks.is_empty() {
+        chunks.push(retrieved.clone());
+    }
+    chunks.sort_by_key(|c| chunk_field(c, CHUNK_INDEX_FIELD));
+
+    let mut content = String::new();
+    let mut covered = 0;
+    for chunk in chunks.iter() {
+        let start = chunk_field(chunk, CHUNK_START_FIELD);
+        if start >= covered {
+            if start > covered && !content.is_empty() {
+                content.push(' ');
+            }
+            content.push_str(&chunk.page_content);
+        } else if let Some(rest) = chunk.page_content.get(covered - start..) {
+            content.push_str(rest);
+        }
+        covered = covered.max(start + chunk.page_content.len());
+    }
+
+    let mut metadata = retrieved.metadata;
+    for field in [
+        PARENT_ID_FIELD,
+        CHUNK_INDEX_FIELD,
+        CHUNK_COUNT_FIELD,
+        CHUNK_START_FIELD,
+    ] {
+        metadata.remove(field);
+    }
+    let mut parent = Document::new(content).with_metadata(metadata);
+    parent.score = retrieved.score;
+    parent
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunker() -> Chunker {
+        Chunker::new(&Chunking {
+            strategy: ChunkStrategy::Sentence,
+            size: 25,
+            overlap: 0,
+        })
+    }
+
+    #[test]
+    fn chunks_of_a_longer_version_are_not_reassembled() {
+        let old = chunker().split_document(
+            Document::new("First sentence here. Second sentence here. Third sentence here."),
+            "book-1",
+        );
+        let new =
+            chunker().split_document(Document::new("First sentence here. Second one."), "book-1");
+        assert_eq!((old.len(), new.len()), (3, 2));
+
+        // the third chunk of the old version was not replaced by the new version
+        let stored = vec![new[0].clone(), new[1].clone(), old[2].clone()];
+        let parents = reassemble(vec![new[1].clone()], stored.clone());
+        assert_eq!(parents.len(), 1);
+        assert_eq!(parents[0].page_content, "First sentence here. Second one.");
+        assert!(parent_id(&parents[0]).is_none());
+
+        // a stale chunk retrieved on its own is not stitched to the new version
+        let parents = reassemble(vec![old[2].clone()], stored);
+        assert_eq!(parents[0].page_content, "Third sentence here.");
+    }
+}
@@ -0,0 +1,448 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Error, ErrorKind};
+use std::result::Result;
+
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Deserialize};
+
+use super::{
+    config_praser::Domain,
+    topic_clasifier::{OLLAMAChatModelError, TopicClassifier},
+};
+
+// one line of the labeled jsonl file: {"query": "...", "label": "book"}
+#[derive(Debug, Clone, Deserialize)]
+pub struct LabeledQuery {
+    pub query: String,
+    pub label: String,
+}
+
+// anything giving the score of each label for a query, best first
+#[async_trait]
+pub trait TopicScorer {
+    async fn scores(&self, query: &str) -> Result<Vec<(String, f64)>, OLLAMAChatModelError>;
+}
+
+#[async_trait]
+impl TopicScorer for TopicClassifier {
+    async fn scores(&self, query: &str) -> Result<Vec<(String, f64)>, OLLAMAChatModelError> {
+        TopicClassifier::scores(self, query).await
+    }
+}
+
+// keyword classifier used to check the harness without any model: a label scores the share of
+// query words found in its label, description and examples
+pub struct MockClassifier {
+    keywords: Vec<(String, Vec<String>)>,
+}
+
+impl MockClassifier {
+    pub fn new(domains: &[Domain]) -> Self {
+        let keywords = domains
+            .iter()
+            .map(|d| {
+                let text = format!("{} {} {}", d.label, d.description, d.examples.join(" "));
+                (d.label.clone(), words(&text))
+            })
+            .collect();
+        Self { keywords }
+    }
+}
+
+#[async_trait]
+impl TopicScorer for MockClassifier {
+    async fn scores(&self, query: &str) -> Result<Vec<(String, f64)>, OLLAMAChatModelError> {
+        let query_words = words(query);
+        let hits = self
+            .keywords
+            .iter()
+            .map(|(label, keywords)| {
+                let hits = query_words
+                    .iter()
+                    .filter(|w| {
+                        keywords
+                            .iter()
+                            .any(|k| w.starts_with(k) || k.starts_with(*w))
+                    })
+                    .count();
+                (label.clone(), hits as f64)
+            })
+            .collect::<Vec<(String, f64)>>();
+
+        let total = hits.iter().map(|(_, h)| h).sum::<f64>();
+        if total == 0.0 {
+            return Ok(vec![("other".to_string(), 1.0)]);
+        }
+
+        let mut scores = hits
+            .into_iter()
+            .map(|(label, h)| (label, h / total))
+            .collect::<Vec<(String, f64)>>();
+        scores.sort_by(|a, b| b.1.total_cmp(&a.1));
+        Ok(scores)
+    }
+}
+
+// lowercase words longer than 3 chars, shorter ones are mostly stop words
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| w.len() > 3)
+        .map(|w| w.to_lowercase())
+        .collect()
+}
+
+// one json object per line, blank lines are skipped
+pub fn load_jsonl<T: DeserializeOwned>(file_path: &str) -> Result<Vec<T>, Error> {
+    let file = File::open(file_path)?;
+    let mut items = Vec::<T>::new();
+
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let item = serde_json::from_str::<T>(&line).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid json at line {}: {}", i + 1, e),
+            )
+        })?;
+        items.push(item);
+    }
+
+    Ok(items)
+}
+
+#[derive(Debug, Clone)]
+pub struct LabelMetrics {
+    pub label: String,
+    pub precision: f64,
+    pub recall: f64,
+    pub f1: f64,
+    pub support: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct EvalReport {
+    pub labels: Vec<String>,
+    pub threshold: f64,
+    // confusion[expected][predicted], indexed like `labels`
+    pub confusion: Vec<Vec<usize>>,
+    pub metrics: Vec<LabelMetrics>,
+    pub accuracy: f64,
+    pub macro_f1: f64,
+    pub best_threshold: f64,
+    pub best_macro_f1: f64,
+    pub errors: usize,
+}
+
+// run every query through the scorer once, then measure the routing at the configured
+// threshold and sweep thresholds from 0.05 to 0.95 to find the one with the best macro f1
+pub async fn evaluate(
+    scorer: &(dyn TopicScorer + Sync),
+    domains: &[Domain],
+    queries: &[LabeledQuery],
+    threshold: f64,
+) -> EvalReport {
+    let mut labels = domains
+        .iter()
+        .map(|d| d.label.clone())
+        .collect::<Vec<String>>();
+    labels.push("other".to_string());
+    for q in queries {
+        if !labels.iter().any(|l| l.eq_ignore_ascii_case(&q.label)) {
+            labels.push(q.label.clone());
+        }
+    }
+
+    let mut scores = Vec::<Vec<(String, f64)>>::new();
+    let mut errors = 0;
+    for q in queries {
+        match scorer.scores(&q.query).await {
+            Ok(s) => scores.push(s),
+            Err(e) => {
+                println!("Error classifying `{}`: {}", q.query, e);
+                errors += 1;
+                scores.push(Vec::new());
+            }
+        }
+    }
+
+    let (mut best_threshold, mut best_macro_f1) = (threshold, f64::MIN);
+    for step in 1..20 {
+        let t = step as f64 * 0.05;
+        let confusion = confusion_matrix(&labels, queries, &scores, t);
+        let (_, macro_f1) = label_metrics(&labels, &confusion);
+        if macro_f1 > best_macro_f1 {
+            best_threshold = t;
+            best_macro_f1 = macro_f1;
+        }
+    }
+
+    let confusion = confusion_matrix(&labels, queries, &scores, threshold);
+    let (metrics, macro_f1) = label_metrics(&labels, &confusion);
+    let correct = (0..labels.len()).map(|i| confusion[i][i]).sum::<usize>();
+
+    EvalReport {
+        labels,
+        threshold,
+        confusion,
+        metrics,
+        accuracy: correct as f64 / queries.len().max(1) as f64,
+        macro_f1,
+        best_threshold,
+        best_macro_f1,
+        errors,
+    }
+}
+
+// the predicted label is the best one above the threshold, "other" when none is
+fn predict(scores: &[(String, f64)], threshold: f64) -> &str {
+    scores
+        .iter()
+        .filter(|(_, score)| *score > threshold)
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(label, _)| label.as_str())
+        .unwrap_or("other")
+}
+
+fn confusion_matrix(
+    labels: &[String],
+    queries: &[LabeledQuery],
+    scores: &[Vec<(String, f64)>],
+    threshold: f64,
+) -> Vec<Vec<usize>> {
+    // labels unknown to the domains count as "other"
+    let other = labels.iter().position(|l| l == "other").unwrap();
+    let index = |label: &str| {
+        labels
+            .iter()
+            .position(|l| l.eq_ignore_ascii_case(label))
+            .unwrap_or(other)
+    };
+    let mut confusion = vec![vec![0; labels.len()]; labels.len()];
+    for (q, s) in queries.iter().zip(scores) {
+        confusion[index(&q.label)][index(predict(s, threshold))] += 1;
+    }
+    confusion
+}
+
+fn label_metrics(labels: &[String], confusion: &[Vec<usize>]) -> (Vec<LabelMetrics>, f64) {
+    let ratio = |a: usize, b: usize| if b == 0 { 0.0 } else { a as f64 / b as f64 };
+
+    let metrics = labels
+        .iter()
+        .enumerate()
+        .map(|(i, label)| {
+            let true_positive = confusion[i][i];
+            let predicted = confusion.iter().map(|row| row[i]).sum::<usize>();
+            let support = confusion[i].iter().sum::<usize>();
+            let precision = ratio(true_positive, predicted);
+            let recall = ratio(true_positive, support);
+            let f1 = if precision + recall == 0.0 {
+                0.0
+            } else {
+                2.0 * precision * recall / (precision + recall)
+            };
+            LabelMetrics {
+                label: label.clone(),
+                precision,
+                recall,
+                f1,
+                support,
+            }
+        })
+        .collect::<Vec<LabelMetrics>>();
+
+    // labels missing from the evaluation set do not count in the average
+    let evaluated = metrics
+        .iter()
+        .filter(|m| m.support > 0)
+        .collect::<Vec<&LabelMetrics>>();
+    let macro_f1 = evaluated.iter().map(|m| m.f1).sum::<f64>() / evaluated.len().max(1) as f64;
+
+    (metrics, macro_f1)
+}
+
+impl fmt::Display for EvalReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let width = self
+            .labels
+            .iter()
+            .map(|l| l.len())
+            .max()
+            .unwrap_or(0)
+            .max(9);
+
+        writeln!(f, "threshold {:.2}", self.threshold)?;
+        writeln!(
+            f,
+            "{:<width$} {:>9} {:>9} {:>9} {:>9}",
+            "label", "precision", "recall", "f1", "support"
+        )?;
+        for m in self.metrics.iter() {
+            writeln!(
+                f,
+                "{:<width$} {:>9.3} {:>9.3} {:>9.3} {:>9}",
+                m.label, m.precision, m.recall, m.f1, m.support
+            )?;
+        }
+        writeln!(
+            f,
+            "accuracy {:.3}, macro f1 {:.3}",
+            self.accuracy, self.macro_f1
+        )?;
+
+        writeln!(f, "\nconfusion matrix (rows expected, columns predicted)")?;
+        write!(f, "{:<width$}", "")?;
+        for label in self.labels.iter() {
+            write!(f, " {:>width$}", label)?;
+        }
+        writeln!(f)?;
+        for (label, row) in self.labels.iter().zip(self.confusion.iter()) {
+            write!(f, "{:<width$}", label)?;
+            for count in row {
+                write!(f, " {:>width$}", count)?;
+            }
+            writeln!(f)?;
+        }
+
+        if self.errors > 0 {
+            writeln!(
+                f,
+                "\n{} queries failed and were counted as `other`",
+                self.errors
+            )?;
+        }
+        write!(
+            f,
+            "\nbest threshold {:.2} (macro f1 {:.3}), set `classifier.threshold` in config.toml",
+            self.best_threshold, self.best_macro_f1
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn domain(label: &str, description: &str, examples: &[&str]) -> Domain {
+        let mut domain: Domain = toml::from_str(&format!(
+            "label = \"{label}\"\ndescription = \"{description}\"\ncollection = \"{label}s\"\n\
+            data_path = \"{label}.json\"\nloader = \"{label}\""
+        ))
+        .unwrap();
+        domain.examples = examples.iter().map(|e| e.to_string()).collect();
+        domain
+    }
+
+    fn domains() -> Vec<Domain> {
+        vec![
+            domain(
+                "book",
+                "books, novels, authors and reading",
+                &["Recommend me a fantasy novel"],
+            ),
+            domain(
+                "movie",
+                "movies, films, actors and directors",
+                &["Suggest a horror movie"],
+            ),
+        ]
+    }
+
+    // best label and its score for each query
+    struct FixedScorer(Vec<(&'static str, &'static str, f64)>);
+
+    #[async_trait]
+    impl TopicScorer for FixedScorer {
+        async fn scores(&self, query: &str) -> Result<Vec<(String, f64)>, OLLAMAChatModelError> {
+            let (_, label, score) = self.0.iter().find(|(q, _, _)| *q == query).unwrap();
+            Ok(vec![(label.to_string(), *score)])
+        }
+    }
+
+    fn labeled(scorer: &FixedScorer, labels: &[&str]) -> Vec<LabeledQuery> {
+        scorer
+            .0
+            .iter()
+            .zip(labels)
+            .map(|((query, _, _), label)| LabeledQuery {
+                query: query.to_string(),
+                label: label.to_string(),
+            })
+            .collect()
+    }
+
+    fn assert_close(found: f64, expected: f64) {
+        assert!(
+            (found - expected).abs() < 1e-9,
+            "{} instead of {}",
+            found,
+            expected
+        );
+    }
+
+    #[tokio::test]
+    async fn mock_classifier_scores_the_keywords_of_each_label() {
+        let mock = MockClassifier::new(&domains());
+        let scores = mock.scores("recommend a fantasy novel").await.unwrap();
+        assert_eq!(scores[0], ("book".to_string(), 1.0));
+        assert_eq!(scores[1], ("movie".to_string(), 0.0));
+
+        let scores = mock.scores("movies or novels").await.unwrap();
+        assert_close(scores[0].1, 0.5);
+        assert_close(scores[1].1, 0.5);
+
+        let scores = mock.scores("hello there").await.unwrap();
+        assert_eq!(scores, vec![("other".to_string(), 1.0)]);
+    }
+
+    #[tokio::test]
+    async fn metrics_confusion_and_threshold_sweep() {
+        let scorer = FixedScorer(vec![
+            ("q1", "book", 0.9),
+            ("q2", "book", 0.6),
+            ("q3", "movie", 0.8),
+            ("q4", "movie", 0.9),
+            ("q5", "book", 0.7),
+            // only routed to "book" below a threshold of 0.42
+            ("q6", "book", 0.42),
+        ]);
+        let queries = labeled(
+            &scorer,
+            &["book", "book", "book", "movie", "movie", "other"],
+        );
+
+        let report = evaluate(&scorer, &domains(), &queries, 0.5).await;
+        assert_eq!(report.labels, vec!["book", "movie", "other"]);
+        assert_eq!(
+            report.confusion,
+            vec![vec![2, 1, 0], vec![1, 1, 0], vec![0, 0, 1]]
+        );
+
+        // book: 2 true positives, 1 false positive (q5), 1 false negative (q3)
+        let book = &report.metrics[0];
+        assert_close(book.precision, 2.0 / 3.0);
+        assert_close(book.recall, 2.0 / 3.0);
+        assert_close(book.f1, 2.0 / 3.0);
+        assert_eq!(book.support, 3);
+        let movie = &report.metrics[1];
+        assert_close(movie.precision, 0.5);
+        assert_close(movie.recall, 0.5);
+        assert_close(report.metrics[2].f1, 1.0);
+        assert_close(report.accuracy, 4.0 / 6.0);
+        assert_close(report.macro_f1, (2.0 / 3.0 + 0.5 + 1.0) / 3.0);
+
+        // q6 is a false positive of "book" up to 0.40 and "other" from 0.45, q2 falls to "other"
+        // from 0.60
+        assert_close(report.best_threshold, 0.45);
+        assert_close(report.best_macro_f1, report.macro_f1);
+
+        let report = evaluate(&scorer, &domains(), &queries, 0.4).await;
+        assert_eq!(report.confusion[2], vec![1, 0, 0]);
+        assert_close(report.metrics[0].precision, 0.5);
+        assert_close(report.metrics[2].f1, 0.0);
+    }
+}
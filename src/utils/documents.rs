@@ -0,0 +1,209 @@
+use std::{
+    collections::HashMap,
+    fs::{read_dir, read_to_string},
+    path::{Path, PathBuf},
+};
+
+use langchain_rust::schemas::Document;
+use pulldown_cmark::{Event, Parser, Tag, TagEnd};
+use scraper::{node::Node, Html, Selector};
+use serde_json::{json, Value};
+
+use super::{
+    config_praser::ColumnMapping,
+    records::{RecordError, RecordResult, Records},
+    vector_space::DataLoader,
+};
+
+// extensions of the files read by the "documents" loader
+const EXTENSIONS: [&str; 5] = ["md", "markdown", "txt", "html", "pdf"];
+
+// loader of a directory of markdown, text, html and pdf files (reviews, reading guides, notes).
+// each file is one record with its `path`, `title`, `format` and extracted `text`
+#[derive(Debug)]
+pub struct FileDataLoader;
+
+impl DataLoader for FileDataLoader {
+    // the files are read one at a time, the column mapping does not apply
+    fn records(&self, file_path: &str, _mapping: &ColumnMapping) -> RecordResult<Records> {
+        let files = document_files(Path::new(file_path))?;
+        Ok(Box::new(files.into_iter().map(|path| read_file(&path))))
+    }
+
+    // the text is the page content, the other fields are the metadata
+    fn create_document(&self, item: &Value) -> RecordResult<Document> {
+        let obj = item.as_object().ok_or(RecordError::NotAnObject)?;
+        let meta_data = obj
+            .iter()
+            .filter(|(k, _)| k.as_str() != "text")
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect::<HashMap<String, Value>>();
+
+        Ok(Document::new(self.get_page_content(item)?).with_metadata(meta_data))
+    }
+
+    fn count_records(&self, file_path: &str, _mapping: &ColumnMapping) -> RecordResult<u64> {
+        Ok(document_files(Path::new(file_path))?.len() as u64)
+    }
+
+    fn get_page_content(&self, item: &Value) -> RecordResult<String> {
+        match item.get("text").and_then(|t| t.as_str()) {
+            Some(text) if !text.trim().is_empty() => Ok(text.to_string()),
+            _ => Err(RecordError::MissingField("text".to_string())),
+        }
+    }
+}
+
+// the supported files of the directory and of its sub directories, sorted by path. a file
+// path is read on its own
+pub fn document_files(path: &Path) -> RecordResult<Vec<PathBuf>> {
+    if path.is_file() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let mut files = Vec::<PathBuf>::new();
+    let mut dirs = vec![path.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in read_dir(&dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if EXTENSIONS.contains(&extension(&path).as_str()) {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn extension(path: &Path) -> String {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_lowercase()
+}
+
+// record of a file, the title is the first heading or the pdf title when there is one, the
+// file name otherwise
+fn read_file(path: &Path) -> RecordResult<Value> {
+    let format = match extension(path).as_str() {
+        "markdown" => "md".to_string(),
+        e => e.to_string(),
+    };
+    let (title, text) = match format.as_str() {
+        "md" => markdown_text(&read_to_string(path)?),
+        "html" => html_text(&read_to_string(path)?),
+        "pdf" => pdf_text(path)?,
+        _ => (None, read_to_string(path)?),
+    };
+    let title = title.filter(|t| !t.is_empty()).unwrap_or_else(|| {
+        path.file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default()
+    });
+
+    Ok(json!({
+        "path": path.to_string_lossy(),
+        "title": title,
+        "format": format,
+        "text": text.trim(),
+    }))
+}
+
+// text of the markdown without its syntax, one block per paragraph
+fn markdown_text(markdown: &str) -> (Option<String>, String) {
+    let mut title = None::<String>;
+    let mut heading = None::<String>;
+    let mut text = String::new();
+
+    for event in Parser::new(markdown) {
+        match event {
+            Event::Start(Tag::Heading { .. }) => heading = Some(String::new()),
+            Event::End(TagEnd::Heading(_)) => {
+                if title.is_none() {
+                    title = heading.take().map(|h| h.trim().to_string());
+                }
+                heading = None;
+                text.push_str("\n\n");
+            }
+            Event::Text(t) | Event::Code(t) => {
+                if let Some(h) = heading.as_mut() {
+                    h.push_str(&t);
+                }
+                text.push_str(&t);
+            }
+            Event::SoftBreak | Event::HardBreak => text.push('\n'),
+            Event::End(TagEnd::Paragraph | TagEnd::Item | TagEnd::CodeBlock) => {
+                text.push_str("\n\n")
+            }
+            _ => {}
+        }
+    }
+    (title, text)
+}
+
+// visible text of the page, without its scripts and styles
+fn html_text(html: &str) -> (Option<String>, String) {
+    let document = Html::parse_document(html);
+    let title = ["title", "h1"].iter().find_map(|name| {
+        let selector = Selector::parse(name).unwrap();
+        document
+            .select(&selector)
+            .next()
+            .map(|e| e.text().collect::<String>().trim().to_string())
+            .filter(|t| !t.is_empty())
+    });
+
+    let text = document
+        .tree
+        .root()
+        .descendants()
+        .filter_map(|node| match node.value() {
+            Node::Text(t) => {
+                let hidden = node
+                    .parent()
+                    .and_then(|p| p.value().as_element())
+                    .map(|e| matches!(e.name(), "script" | "style" | "noscript" | "title"))
+                    .unwrap_or(false);
+                (!hidden && !t.trim().is_empty()).then(|| t.trim().to_string())
+            }
+            _ => None,
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+    (title, text)
+}
+
+fn pdf_text(path: &Path) -> RecordResult<(Option<String>, String)> {
+    let pdf = lopdf::Document::load(path)?;
+    let pages = pdf.get_pages().keys().copied().collect::<Vec<u32>>();
+    let text = pdf.extract_text(&pages)?;
+
+    let title = pdf
+        .trailer
+        .get(b"Info")
+        .and_then(|info| pdf.dereference(info))
+        .and_then(|(_, info)| info.as_dict())
+        .and_then(|info| info.get(b"Title"))
+        .and_then(|title| title.as_str())
+        .map(decode_pdf_string)
+        .ok();
+    Ok((title, text))
+}
+
+// pdf text strings are utf-16 with a byte order mark, or single byte encoded
+fn decode_pdf_string(bytes: &[u8]) -> String {
+    match bytes {
+        [0xfe, 0xff, rest @ ..] => {
+            let units = rest
+                .chunks_exact(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                .collect::<Vec<u16>>();
+            String::from_utf16_lossy(&units)
+        }
+        _ => bytes.iter().map(|b| *b as char).collect(),
+    }
+    .trim()
+    .to_string()
+}
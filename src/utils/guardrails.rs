@@ -0,0 +1,393 @@
+use std::fmt;
+
+use async_trait::async_trait;
+use langchain_rust::{
+    language_models::llm::LLM,
+    llm::{OpenAI, OpenAIConfig},
+};
+use regex::Regex;
+use serde::Deserialize;
+
+use super::config_praser::Guardrails;
+
+// where a check runs: on the user query, on each retrieved document (e.g. injections hidden in a
+// catalog description) or on the answer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+    Input,
+    Context,
+    Output,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stage::Input => write!(f, "input"),
+            Stage::Context => write!(f, "context"),
+            Stage::Output => write!(f, "output"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GuardrailKind {
+    // refuse texts matching one of the `blocklist` patterns
+    Blocklist,
+    // replace emails, phone and card numbers by a placeholder
+    Pii,
+    // ask the chat model whether the text follows the `moderation_policy`
+    Moderation,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    Allow,
+    // the text goes on with its replacement
+    Redact(String),
+    Refuse,
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Verdict::Allow => write!(f, "allow"),
+            Verdict::Redact(_) => write!(f, "redact"),
+            Verdict::Refuse => write!(f, "refuse"),
+        }
+    }
+}
+
+#[async_trait]
+pub trait Guardrail: Send + Sync {
+    fn name(&self) -> &str;
+    // verdict on the text and its reason
+    async fn check(&self, text: &str) -> (Verdict, String);
+}
+
+pub struct RegexBlocklist {
+    patterns: Vec<Regex>,
+}
+
+impl RegexBlocklist {
+    pub fn new(patterns: &[String]) -> Result<Self, regex::Error> {
+        let patterns = patterns
+            .iter()
+            .map(|p| Regex::new(p))
+            .collect::<Result<Vec<Regex>, regex::Error>>()?;
+        Ok(Self { patterns })
+    }
+}
+
+#[async_trait]
+impl Guardrail for RegexBlocklist {
+    fn name(&self) -> &str {
+        "blocklist"
+    }
+
+    async fn check(&self, text: &str) -> (Verdict, String) {
+        match self.patterns.iter().find(|p| p.is_match(text)) {
+            Some(pattern) => (Verdict::Refuse, format!("matches `{}`", pattern)),
+            None => (Verdict::Allow, "no blocked pattern".to_string()),
+        }
+    }
+}
+
+// whether a match of a pii pattern, at its byte range of the text, is personal data
+type PiiCheck = fn(&str, usize, usize) -> bool;
+
+pub struct PiiRedactor {
+    // (pattern, replacement, check of the matches)
+    patterns: Vec<(Regex, &'static str, PiiCheck)>,
+}
+
+impl Default for PiiRedactor {
+    fn default() -> Self {
+        let patterns: [(&str, &'static str, PiiCheck); 3] = [
+            (
+                r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}",
+                "[email]",
+                |_, _, _| true,
+            ),
+            (r"\b\d(?:[ -]?\d){12,15}\b", "[card number]", is_card_number),
+            (
+                r"(?:\+?\d{1,3}[ .-]?)?\(?\d{3}\)?[ .-]?\d{3}[ .-]?\d{4}\b",
+                "[phone number]",
+                |text, start, end| !is_isbn(text, start, end),
+            ),
+        ];
+        Self {
+            patterns: patterns
+                .into_iter()
+                .map(|(p, r, check)| (Regex::new(p).unwrap(), r, check))
+                .collect(),
+        }
+    }
+}
+
+fn digits(text: &str) -> Vec<u32> {
+    text.chars().filter_map(|c| c.to_digit(10)).collect()
+}
+
+// card numbers pass the luhn checksum, isbn-13 of a book may pass it too
+fn is_card_number(text: &str, start: usize, end: usize) -> bool {
+    if is_isbn(text, start, end) {
+        return false;
+    }
+    let sum = digits(&text[start..end])
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, d)| match (i % 2, d * 2) {
+            (0, _) => *d,
+            (_, doubled) if doubled > 9 => doubled - 9,
+            (_, doubled) => doubled,
+        })
+        .sum::<u32>();
+    sum % 10 == 0
+}
+
+// isbn-13 start with 978 or 979. an isbn-10 is 9 digits and an x check digit, or 10 digits with
+// a valid check digit after an "isbn" label, other ones look like phone numbers
+fn is_isbn(text: &str, start: usize, end: usize) -> bool {
+    let digits = digits(&text[start..end]);
+    match digits.len() {
+        13 => return digits[..2] == [9, 7] && matches!(digits[2], 8 | 9),
+        9 => return text[end..].starts_with(['X', 'x']),
+        10 => {}
+        _ => return false,
+    }
+    let checksum = digits
+        .iter()
+        .enumerate()
+        .map(|(i, d)| (10 - i as u32) * d)
+        .sum::<u32>();
+    let label = text[..start]
+        .char_indices()
+        .rev()
+        .take(12)
+        .last()
+        .map(|(i, _)| text[i..start].to_lowercase())
+        .unwrap_or_default();
+    checksum % 11 == 0 && label.contains("isbn")
+}
+
+#[async_trait]
+impl Guardrail for PiiRedactor {
+    fn name(&self) -> &str {
+        "pii"
+    }
+
+    async fn check(&self, text: &str) -> (Verdict, String) {
+        let mut redacted = text.to_string();
+        let mut found = Vec::<&str>::new();
+        for (pattern, replacement, check) in self.patterns.iter() {
+            let mut replaced = String::new();
+            let mut last = 0;
+            for m in pattern.find_iter(&redacted) {
+                if check(&redacted, m.start(), m.end()) {
+                    replaced.push_str(&redacted[last..m.start()]);
+                    replaced.push_str(replacement);
+                    last = m.end();
+                }
+            }
+            if last > 0 {
+                replaced.push_str(&redacted[last..]);
+                redacted = replaced;
+                found.push(replacement);
+            }
+        }
+
+        if found.is_empty() {
+            (Verdict::Allow, "no personal data".to_string())
+        } else {
+            (
+                Verdict::Redact(redacted),
+                format!("found {}", found.join(", ")),
+            )
+        }
+    }
+}
+
+// json answer expected from the moderation model
+#[derive(Debug, Deserialize)]
+struct ModerationAnswer {
+    allowed: bool,
+    #[serde(default)]
+    reason: String,
+}
+
+pub struct LlmModeration {
+    llm: OpenAI<OpenAIConfig>,
+    policy: String,
+}
+
+impl LlmModeration {
+    pub fn new(llm: OpenAI<OpenAIConfig>, policy: String) -> Self {
+        Self { llm, policy }
+    }
+}
+
+#[async_trait]
+impl Guardrail for LlmModeration {
+    fn name(&self) -> &str {
+        "moderation"
+    }
+
+    // the text is allowed when the model is unavailable or its answer is malformed
+    async fn check(&self, text: &str) -> (Verdict, String) {
+        let prompt = format!(
+            "You are a content moderator. Policy:\n{}\n\nDoes the following text follow the policy? \
+            Answer only with a JSON object like {{\"allowed\": true, \"reason\": \"<short reason>\"}}.\n\n\
+            Text: {}\n",
+            self.policy, text
+        );
+
+        let answer = match self.llm.invoke(&prompt).await {
+            Ok(answer) => answer,
+            Err(e) => return (Verdict::Allow, format!("moderation unavailable: {}", e)),
+        };
+        let parsed = match (answer.find('{'), answer.rfind('}')) {
+            (Some(start), Some(end)) if start < end => {
+                serde_json::from_str::<ModerationAnswer>(&answer[start..=end]).ok()
+            }
+            _ => None,
+        };
+
+        match parsed {
+            Some(m) if m.allowed => (Verdict::Allow, m.reason),
+            Some(m) => (Verdict::Refuse, m.reason),
+            None => (
+                Verdict::Allow,
+                format!("malformed moderation answer: {}", answer),
+            ),
+        }
+    }
+}
+
+// checks of each stage, run in order. a redaction is passed to the next check, a refusal stops
+// the chain
+#[derive(Default)]
+pub struct GuardrailChain {
+    input: Vec<Box<dyn Guardrail>>,
+    context: Vec<Box<dyn Guardrail>>,
+    output: Vec<Box<dyn Guardrail>>,
+}
+
+impl GuardrailChain {
+    // the blocklist patterns are validated by `load_config`
+    pub fn from_config(config: &Guardrails, llm: OpenAI<OpenAIConfig>) -> Self {
+        if !config.enabled {
+            return Self::default();
+        }
+
+        let build = |kinds: &[GuardrailKind]| {
+            kinds
+                .iter()
+                .map(|kind| -> Box<dyn Guardrail> {
+                    match kind {
+                        GuardrailKind::Blocklist => {
+                            Box::new(RegexBlocklist::new(&config.blocklist).unwrap())
+                        }
+                        GuardrailKind::Pii => Box::new(PiiRedactor::default()),
+                        GuardrailKind::Moderation => Box::new(LlmModeration::new(
+                            llm.clone(),
+                            config.moderation_policy.clone(),
+                        )),
+                    }
+                })
+                .collect::<Vec<Box<dyn Guardrail>>>()
+        };
+
+        Self {
+            input: build(&config.input),
+            context: build(&config.context),
+            output: build(&config.output),
+        }
+    }
+
+    // the text to go on with, or the reason of the refusal
+    pub async fn check(&self, stage: Stage, text: &str) -> Result<String, String> {
+        let checks = match stage {
+            Stage::Input => &self.input,
+            Stage::Context => &self.context,
+            Stage::Output => &self.output,
+        };
+
+        let mut text = text.to_string();
+        for check in checks.iter() {
+            let (verdict, reason) = check.check(&text).await;
+            println!(
+                "guardrail {} {}: {} ({})",
+                stage,
+                check.name(),
+                verdict,
+                reason
+            );
+            match verdict {
+                Verdict::Allow => {}
+                Verdict::Redact(redacted) => text = redacted,
+                Verdict::Refuse => return Err(reason),
+            }
+        }
+        Ok(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn redact(text: &str) -> String {
+        match PiiRedactor::default().check(text).await {
+            (Verdict::Redact(redacted), _) => redacted,
+            (verdict, _) => {
+                assert_eq!(verdict, Verdict::Allow);
+                text.to_string()
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn isbns_are_not_redacted() {
+        for text in [
+            "Dune, ISBN 978-0-441-01359-3",
+            "Dune (9780441013593) by Frank Herbert",
+            "isbn-13: 979-8-6022-7318-0",
+            "ISBN 0441013597",
+            "ISBN-10: 0-441-01359-7",
+            "ISBN 080442957X",
+        ] {
+            assert_eq!(redact(text).await, text);
+        }
+        assert!(is_isbn("080442957X", 0, 9));
+    }
+
+    #[tokio::test]
+    async fn cards_need_a_valid_luhn_checksum() {
+        assert_eq!(
+            redact("card 4111 1111 1111 1111 expires soon").await,
+            "card [card number] expires soon"
+        );
+        assert_eq!(
+            redact("pay with 5500-0000-0000-0004").await,
+            "pay with [card number]"
+        );
+        // a 16 digit order reference failing the checksum is kept
+        assert!(!is_card_number("order 4111 1111 1111 1112", 6, 25));
+    }
+
+    #[tokio::test]
+    async fn phones_and_emails_are_redacted() {
+        assert_eq!(
+            redact("call me at 555-123-4567 or jane@example.com").await,
+            "call me at [phone number] or [email]"
+        );
+        assert_eq!(
+            redact("office +1 (555) 123-4567").await,
+            "office [phone number]"
+        );
+        // a phone number passing the isbn-10 checksum without a label
+        assert_eq!(redact("call 0441013597").await, "call [phone number]");
+    }
+}
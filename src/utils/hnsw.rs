@@ -0,0 +1,301 @@
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashSet},
+};
+
+// parameters of the hnsw graph: links per node, candidates kept while inserting and while
+// searching
+#[derive(Debug, Clone, Copy)]
+pub struct HnswParams {
+    pub m: usize,
+    pub ef_construction: usize,
+    pub ef_search: usize,
+}
+
+// node of a search, ordered by similarity
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Scored(f32, usize);
+
+impl Eq for Scored {}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0).then(self.1.cmp(&other.1))
+    }
+}
+
+// hierarchical navigable small world graph for approximate cosine search. the vectors are
+// normalized so the similarity is their dot product
+#[derive(Debug)]
+pub struct Hnsw {
+    params: HnswParams,
+    vectors: Vec<Vec<f32>>,
+    // neighbours of each node on each of its layers
+    links: Vec<Vec<Vec<usize>>>,
+    entry: Option<usize>,
+    // state of the level generator, fixed so the same documents give the same graph
+    seed: u64,
+}
+
+impl Hnsw {
+    pub fn new(params: HnswParams) -> Self {
+        Self {
+            params: HnswParams {
+                m: params.m.max(2),
+                ef_construction: params.ef_construction.max(1),
+                ef_search: params.ef_search.max(1),
+            },
+            vectors: Vec::new(),
+            links: Vec::new(),
+            entry: None,
+            seed: 0x9e3779b97f4a7c15,
+        }
+    }
+
+    // add a vector, its node is its insertion position
+    pub fn insert(&mut self, vector: &[f32]) -> usize {
+        let node = self.vectors.len();
+        let level = self.random_level();
+        self.vectors.push(normalize(vector));
+        self.links.push(vec![Vec::new(); level + 1]);
+
+        let Some(entry) = self.entry else {
+            self.entry = Some(node);
+            return node;
+        };
+
+        let top = self.links[entry].len() - 1;
+        let query = self.vectors[node].clone();
+        let mut nearest = vec![Scored(self.similarity(&query, entry), entry)];
+        for layer in (level + 1..=top).rev() {
+            nearest = self.search_layer(&query, &nearest, 1, layer);
+        }
+
+        for layer in (0..=level.min(top)).rev() {
+            nearest = self.search_layer(&query, &nearest, self.params.ef_construction, layer);
+            let max_links = self.max_links(layer);
+            let neighbours = nearest
+                .iter()
+                .take(self.params.m)
+                .map(|s| s.1)
+                .collect::<Vec<usize>>();
+
+            for &neighbour in neighbours.iter() {
+                self.links[neighbour][layer].push(node);
+                if self.links[neighbour][layer].len() > max_links {
+                    self.prune(neighbour, layer, max_links);
+                }
+            }
+            self.links[node][layer] = neighbours;
+        }
+
+        if level > top {
+            self.entry = Some(node);
+        }
+        node
+    }
+
+    // nodes of the `k` vectors most similar to the query, with their similarity
+    pub fn search(&self, query: &[f32], k: usize) -> Vec<(usize, f32)> {
+        let Some(entry) = self.entry else {
+            return Vec::new();
+        };
+        let query = normalize(query);
+
+        let mut nearest = vec![Scored(self.similarity(&query, entry), entry)];
+        for layer in (1..self.links[entry].len()).rev() {
+            nearest = self.search_layer(&query, &nearest, 1, layer);
+        }
+        nearest = self.search_layer(&query, &nearest, self.params.ef_search.max(k), 0);
+
+        nearest.into_iter().take(k).map(|s| (s.1, s.0)).collect()
+    }
+
+    // the `ef` nodes of the layer closest to the query, most similar first
+    fn search_layer(
+        &self,
+        query: &[f32],
+        entries: &[Scored],
+        ef: usize,
+        layer: usize,
+    ) -> Vec<Scored> {
+        let mut visited = entries.iter().map(|s| s.1).collect::<HashSet<usize>>();
+        let mut candidates = entries.iter().copied().collect::<BinaryHeap<Scored>>();
+        // min heap of the results, the least similar on top
+        let mut results = entries
+            .iter()
+            .map(|s| std::cmp::Reverse(*s))
+            .collect::<BinaryHeap<std::cmp::Reverse<Scored>>>();
+
+        while let Some(candidate) = candidates.pop() {
+            let worst = results.peek().map(|r| r.0 .0).unwrap_or(f32::MIN);
+            if candidate.0 < worst && results.len() >= ef {
+                break;
+            }
+            let Some(neighbours) = self.links[candidate.1].get(layer) else {
+                continue;
+            };
+            for &neighbour in neighbours {
+                if !visited.insert(neighbour) {
+                    continue;
+                }
+                let scored = Scored(self.similarity(query, neighbour), neighbour);
+                let worst = results.peek().map(|r| r.0 .0).unwrap_or(f32::MIN);
+                if results.len() < ef || scored.0 > worst {
+                    candidates.push(scored);
+                    results.push(std::cmp::Reverse(scored));
+                    if results.len() > ef {
+                        results.pop();
+                    }
+                }
+            }
+        }
+
+        let mut results = results.into_iter().map(|r| r.0).collect::<Vec<Scored>>();
+        results.sort_by(|a, b| b.cmp(a));
+        results
+    }
+
+    // keep the most similar links of a node
+    fn prune(&mut self, node: usize, layer: usize, max_links: usize) {
+        let vector = self.vectors[node].clone();
+        let mut scored = self.links[node][layer]
+            .iter()
+            .map(|&n| Scored(self.similarity(&vector, n), n))
+            .collect::<Vec<Scored>>();
+        scored.sort_by(|a, b| b.cmp(a));
+        self.links[node][layer] = scored.into_iter().take(max_links).map(|s| s.1).collect();
+    }
+
+    // the ground layer holds twice as many links
+    fn max_links(&self, layer: usize) -> usize {
+        if layer == 0 {
+            self.params.m * 2
+        } else {
+            self.params.m
+        }
+    }
+
+    fn similarity(&self, query: &[f32], node: usize) -> f32 {
+        query
+            .iter()
+            .zip(self.vectors[node].iter())
+            .map(|(a, b)| a * b)
+            .sum()
+    }
+
+    // exponentially decaying level, from a splitmix64 draw
+    fn random_level(&mut self) -> usize {
+        self.seed = self.seed.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.seed;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^= z >> 31;
+
+        let uniform = ((z >> 11) as f64 + 1.0) / (1u64 << 53) as f64;
+        let level_factor = 1.0 / (self.params.m as f64).ln();
+        (-uniform.ln() * level_factor).floor() as usize
+    }
+}
+
+fn normalize(vector: &[f32]) -> Vec<f32> {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm == 0.0 {
+        return vector.to_vec();
+    }
+    vector.iter().map(|x| x / norm).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // deterministic vectors spread over the sphere
+    fn random_vectors(count: usize, dimensions: usize, seed: u64) -> Vec<Vec<f32>> {
+        let mut state = seed.wrapping_mul(0x9e3779b97f4a7c15) | 1;
+        (0..count)
+            .map(|_| {
+                (0..dimensions)
+                    .map(|_| {
+                        state ^= state << 13;
+                        state ^= state >> 7;
+                        state ^= state << 17;
+                        (state % 2000) as f32 / 1000.0 - 1.0
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn brute_force(vectors: &[Vec<f32>], query: &[f32], k: usize) -> Vec<usize> {
+        let query = normalize(query);
+        let mut scored = vectors
+            .iter()
+            .enumerate()
+            .map(|(node, v)| {
+                let v = normalize(v);
+                Scored(query.iter().zip(v.iter()).map(|(a, b)| a * b).sum(), node)
+            })
+            .collect::<Vec<Scored>>();
+        scored.sort_by(|a, b| b.cmp(a));
+        scored.into_iter().take(k).map(|s| s.1).collect()
+    }
+
+    fn params() -> HnswParams {
+        HnswParams {
+            m: 16,
+            ef_construction: 100,
+            ef_search: 64,
+        }
+    }
+
+    #[test]
+    fn recall_against_brute_force() {
+        let vectors = random_vectors(2000, 32, 1);
+        let mut index = Hnsw::new(params());
+        for (position, vector) in vectors.iter().enumerate() {
+            assert_eq!(index.insert(vector), position);
+        }
+
+        let queries = random_vectors(100, 32, 2);
+        let mut found = 0;
+        for query in queries.iter() {
+            let exact = brute_force(&vectors, query, 10);
+            let approximate = index.search(query, 10);
+            assert_eq!(approximate.len(), 10);
+            assert!(approximate.windows(2).all(|w| w[0].1 >= w[1].1));
+            found += approximate
+                .iter()
+                .filter(|(node, _)| exact.contains(node))
+                .count();
+        }
+        let recall = found as f64 / (queries.len() * 10) as f64;
+        assert!(recall >= 0.95, "recall {}", recall);
+    }
+
+    #[test]
+    fn an_inserted_vector_is_its_own_nearest_neighbour() {
+        let vectors = random_vectors(300, 16, 3);
+        let mut index = Hnsw::new(params());
+        for vector in vectors.iter() {
+            index.insert(vector);
+        }
+        for (node, vector) in vectors.iter().enumerate().step_by(7) {
+            let nearest = index.search(vector, 1);
+            assert_eq!(nearest[0].0, node);
+            assert!((nearest[0].1 - 1.0).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn empty_index_finds_nothing() {
+        let index = Hnsw::new(params());
+        assert!(index.search(&[1.0, 0.0], 5).is_empty());
+    }
+}
@@ -1,13 +1,34 @@
 #![allow(dead_code, unused)]
+use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 use thiserror::Error;
 
-use axum::{response::IntoResponse, routing::post, Json, Router};
+use axum::{extract::State, response::IntoResponse, routing::post, Json, Router};
+use langchain_rust::schemas::Message as AgentMessage;
 use serde::{Deserialize, Serialize};
 
 use reqwest::{Client, Error as ReqwestError, StatusCode, Url};
 use serde_json::json;
 
+use super::{
+    chat_agent::ChatAgent, config_praser::Config, store::VectorStore,
+    topic_clasifier::TopicClassifier,
+};
+
+// virtual model served by the RAG chat agent instead of ollama
+pub const RAG_MODEL_NAME: &str = "chatbot-rag";
+
+#[derive(Clone)]
+pub struct ApiServerState {
+    pub config: Arc<Config>,
+    // shared with the chat loop so an in-memory store is loaded once
+    pub store: Arc<dyn VectorStore>,
+    // built once, the embedding classifier would embed its examples again on each request
+    pub topic_classifier: Arc<TopicClassifier>,
+    pub llm_server_url: String,
+    pub classifier_url: String,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 struct Message {
     role: String,
@@ -31,6 +52,10 @@ struct ChatCompletions {
     repetition_penalty: Option<f32>,
     frequency_penalty: Option<f32>,
     presence_penalty: Option<f32>,
+    // send the single message as is, without the chat template of the model. set by the chat
+    // agent on its rag prompt when `servers.raw_prompt` is set
+    #[serde(default)]
+    raw_prompt: bool,
 }
 
 #[derive(Debug, Deserialize)]
@@ -267,15 +292,93 @@ pub enum OLLAMAChatModelError {
     Exception(),
 }
 
-async fn chat_completions(Json(chat_completions): Json<ChatCompletions>) -> impl IntoResponse {
+// run the request through the chat agent (topic classification + pgvector retrieval)
+// and return the retrieved documents in the `sources` extension field, the ones cited in
+// the answer are also listed in `citations`
+async fn rag_completions(state: ApiServerState, chat_completions: ChatCompletions) -> String {
+    let mut messages = chat_completions.messages;
+    let query_pos = messages.iter().rposition(|m| m.role == "user");
+    let query = match query_pos {
+        Some(pos) => messages.remove(pos).content,
+        None => String::default(),
+    };
+
+    let history = messages
+        .into_iter()
+        .map(|m| match m.role.as_str() {
+            "system" => AgentMessage::new_system_message(m.content),
+            "assistant" => AgentMessage::new_ai_message(m.content),
+            _ => AgentMessage::new_human_message(m.content),
+        })
+        .collect::<Vec<AgentMessage>>();
+
+    let mut chatagent = ChatAgent::from_config(
+        &state.config,
+        state.store.clone(),
+        state.llm_server_url.clone(),
+        state.classifier_url.clone(),
+    )
+    .with_topic_classifier(state.topic_classifier.clone())
+    .with_history(history);
+
+    let promtp_len = query.split(' ').count();
+    let response = chatagent.get_response(query).await;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let completion_len = response.answer.split(' ').count();
+    let a = json!({
+      "id": format!("chatcmpl-{}", promtp_len),
+      "object": "chat.completion",
+      "created": now,
+      "model": RAG_MODEL_NAME,
+      "system_fingerprint": format!("fp_4470{}6fcb", promtp_len),
+      "choices": [{
+        "index": 0,
+        "message": {
+            "role": "assistant",
+            "content": response.answer
+        },
+        "logprobs": null,
+        "finish_reason": "stop"
+      }],
+      "usage": {
+        "prompt_tokens": promtp_len,
+        "completion_tokens": completion_len,
+        "total_tokens": completion_len + promtp_len
+      },
+      "sources": response.sources,
+      "citations": response.citations
+    });
+
+    a.to_string()
+}
+
+// a request flagged `raw_prompt` with a single message is sent to the model as is, without
+// its chat template
+fn is_raw_prompt(chat_completions: &ChatCompletions) -> bool {
+    chat_completions.raw_prompt && chat_completions.messages.len() == 1
+}
+
+async fn chat_completions(
+    State(state): State<ApiServerState>,
+    Json(chat_completions): Json<ChatCompletions>,
+) -> impl IntoResponse {
+    if chat_completions.model == RAG_MODEL_NAME {
+        return rag_completions(state, chat_completions).await;
+    }
+
     let ollma = OLLAMAChatModel::default();
+    let raw_prompt = is_raw_prompt(&chat_completions);
     let msg = chat_completions
         .messages
         .get(0)
         .unwrap()
         .content
         .contains("Use the following pieces of context to answer the questio");
-    if msg {
+    if msg || raw_prompt {
         println!("#########3 generate {:?}", chat_completions);
         let resp = ollma
             .generate(
@@ -300,11 +403,45 @@ async fn chat_completions(Json(chat_completions): Json<ChatCompletions>) -> impl
     }
 }
 
-pub async fn llm_apiserver() {
-    let app = Router::new().route("/v1/chat/completions", post(chat_completions));
+pub async fn llm_apiserver(state: ApiServerState) {
+    let app = Router::new()
+        .route("/v1/chat/completions", post(chat_completions))
+        .with_state(state);
 
     axum::Server::bind(&"127.0.0.1:3000".parse().unwrap())
         .serve(app.into_make_service())
         .await
         .unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    fn request(messages: &[&str], raw_prompt: Option<bool>) -> ChatCompletions {
+        let mut request = json!({
+            "model": "tinyllama:chat",
+            "messages": messages
+                .iter()
+                .map(|content| json!({"role": "user", "content": content}))
+                .collect::<Vec<Value>>()
+        });
+        if let Some(raw_prompt) = raw_prompt {
+            request["raw_prompt"] = Value::from(raw_prompt);
+        }
+        serde_json::from_value(request).unwrap()
+    }
+
+    #[test]
+    fn only_a_flagged_single_message_is_a_raw_prompt() {
+        assert!(is_raw_prompt(&request(&["Hello"], Some(true))));
+        // clients without the flag keep their chat template
+        assert!(!is_raw_prompt(&request(&["Hello"], None)));
+        assert!(!is_raw_prompt(&request(&["Hello"], Some(false))));
+        // a conversation is never sent as one raw prompt
+        assert!(!is_raw_prompt(&request(&["Hello", "Hi"], Some(true))));
+        assert!(!is_raw_prompt(&request(&[], Some(true))));
+    }
+}
@@ -0,0 +1,661 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{create_dir_all, rename, File},
+    io::{BufReader, BufWriter, Write},
+    path::PathBuf,
+    sync::RwLock,
+};
+
+use async_trait::async_trait;
+use langchain_rust::schemas::Document;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use super::{
+    chunker::PARENT_ID_FIELD,
+    hnsw::{Hnsw, HnswParams},
+    retriever::{cosine_similarity, RetrieverError, SearchHit, SearchQuery, CONTENT_HASH_FIELD},
+    store::VectorStore,
+};
+
+// words too common to rank the lexical matches
+const STOP_WORDS: [&str; 12] = [
+    "the", "and", "for", "with", "about", "are", "was", "that", "this", "from", "who", "what",
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredDocument {
+    page_content: String,
+    metadata: HashMap<String, Value>,
+    embedding: Vec<f32>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Collection {
+    metadata: Map<String, Value>,
+    // documents by id
+    documents: BTreeMap<String, StoredDocument>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StoreData {
+    // collections by name, the name is also the collection id
+    collections: BTreeMap<String, Collection>,
+}
+
+// vector store kept in process and saved to a json file after each load, for a laptop without
+// postgres. the search compares the query with every vector, or walks an hnsw index
+pub struct InMemoryStore {
+    path: PathBuf,
+    data: RwLock<StoreData>,
+    hnsw: Option<HnswParams>,
+    // hnsw index of each collection with the document id of each node, built by the first
+    // search after a change of the collection
+    indexes: RwLock<HashMap<String, (Hnsw, Vec<String>)>>,
+}
+
+impl InMemoryStore {
+    // the collections of the file, none when it does not exist yet
+    pub fn open(file_path: &str, hnsw: Option<HnswParams>) -> Result<Self, RetrieverError> {
+        let path = PathBuf::from(file_path);
+        let data = if path.is_file() {
+            serde_json::from_reader(BufReader::new(File::open(&path)?))?
+        } else {
+            StoreData::default()
+        };
+
+        Ok(Self {
+            path,
+            data: RwLock::new(data),
+            hnsw,
+            indexes: RwLock::new(HashMap::new()),
+        })
+    }
+
+    // written to a temporary file first so an interruption keeps the previous file
+    fn save(&self) -> Result<(), RetrieverError> {
+        if let Some(dir) = self.path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            create_dir_all(dir)?;
+        }
+        let tmp_path = self.path.with_extension("tmp");
+        {
+            let data = self.data.read().unwrap();
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            serde_json::to_writer(&mut writer, &*data)?;
+            writer.flush()?;
+        }
+        rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    fn invalidate(&self, collection_name: &str) {
+        self.indexes.write().unwrap().remove(collection_name);
+    }
+
+    // ids of the documents most similar to the query, from the hnsw index of the collection
+    fn hnsw_search(
+        &self,
+        params: HnswParams,
+        collection_name: &str,
+        collection: &Collection,
+        vector: &[f32],
+        limit: usize,
+    ) -> Vec<String> {
+        let mut indexes = self.indexes.write().unwrap();
+        let (index, ids) = indexes
+            .entry(collection_name.to_string())
+            .or_insert_with(|| {
+                let mut index = Hnsw::new(params);
+                let mut ids = Vec::<String>::new();
+                for (id, doc) in collection.documents.iter() {
+                    if doc.embedding.len() == vector.len() {
+                        index.insert(&doc.embedding);
+                        ids.push(id.to_string());
+                    }
+                }
+                (index, ids)
+            });
+        index
+            .search(vector, limit)
+            .into_iter()
+            .map(|(node, _)| ids[node].clone())
+            .collect()
+    }
+}
+
+fn search_hit(id: &str, doc: &StoredDocument, query_vector: &[f64]) -> SearchHit {
+    let embedding = doc
+        .embedding
+        .iter()
+        .map(|x| *x as f64)
+        .collect::<Vec<f64>>();
+    let mut document = Document::new(doc.page_content.clone()).with_metadata(doc.metadata.clone());
+    document.score = cosine_similarity(query_vector, &embedding);
+
+    SearchHit {
+        id: id.to_string(),
+        document,
+        embedding,
+        lexical_match: false,
+        rerank_score: None,
+    }
+}
+
+// lowercase words of the text
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+}
+
+#[async_trait]
+impl VectorStore for InMemoryStore {
+    fn durable_writes(&self) -> bool {
+        false
+    }
+
+    async fn create_collection(
+        &self,
+        collection_name: &str,
+        _dimensions: usize,
+        pre_delete: bool,
+    ) -> Result<String, RetrieverError> {
+        let mut data = self.data.write().unwrap();
+        if pre_delete {
+            data.collections.remove(collection_name);
+        }
+        data.collections
+            .entry(collection_name.to_string())
+            .or_default();
+        self.invalidate(collection_name);
+        Ok(collection_name.to_string())
+    }
+
+    async fn collection_id(&self, collection_name: &str) -> Result<Option<String>, RetrieverError> {
+        let data = self.data.read().unwrap();
+        Ok(data
+            .collections
+            .contains_key(collection_name)
+            .then(|| collection_name.to_string()))
+    }
+
+    async fn table_dimensions(&self) -> Result<Option<usize>, RetrieverError> {
+        Ok(None)
+    }
+
+    async fn collection_dimensions(
+        &self,
+        collection_name: &str,
+    ) -> Result<Option<usize>, RetrieverError> {
+        let data = self.data.read().unwrap();
+        let Some(collection) = data.collections.get(collection_name) else {
+            return Ok(None);
+        };
+        let dimensions = match collection.metadata.get("vector_dimensions") {
+            Some(d) => d.as_u64().map(|d| d as usize),
+            None => collection
+                .documents
+                .values()
+                .next()
+                .map(|d| d.embedding.len()),
+        };
+        Ok(dimensions)
+    }
+
+    async fn set_collection_metadata(
+        &self,
+        collection_name: &str,
+        metadata: Value,
+    ) -> Result<(), RetrieverError> {
+        let mut data = self.data.write().unwrap();
+        if let (Some(collection), Value::Object(fields)) =
+            (data.collections.get_mut(collection_name), metadata)
+        {
+            collection.metadata.extend(fields);
+        }
+        Ok(())
+    }
+
+    async fn content_hashes(
+        &self,
+        collection_id: &str,
+    ) -> Result<HashMap<String, String>, RetrieverError> {
+        let data = self.data.read().unwrap();
+        Ok(data
+            .collections
+            .get(collection_id)
+            .map(|collection| {
+                collection
+                    .documents
+                    .iter()
+                    .map(|(id, doc)| {
+                        let hash = doc
+                            .metadata
+                            .get(CONTENT_HASH_FIELD)
+                            .and_then(|h| h.as_str())
+                            .unwrap_or_default();
+                        (id.to_string(), hash.to_string())
+                    })
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn stored_vectors(
+        &self,
+        collection_id: &str,
+    ) -> Result<Vec<(String, Document, Vec<f64>)>, RetrieverError> {
+        let data = self.data.read().unwrap();
+        Ok(data
+            .collections
+            .get(collection_id)
+            .map(|collection| {
+                collection
+                    .documents
+                    .iter()
+                    .map(|(id, doc)| {
+                        (
+                            id.to_string(),
+                            Document::new("").with_metadata(doc.metadata.clone()),
+                            doc.embedding.iter().map(|x| *x as f64).collect(),
+                        )
+                    })
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn upsert_documents(
+        &self,
+        collection_id: &str,
+        documents: &[(String, Document, Vec<f64>)],
+    ) -> Result<(), RetrieverError> {
+        let mut data = self.data.write().unwrap();
+        let collection = data
+            .collections
+            .get_mut(collection_id)
+            .ok_or(RetrieverError::MissingCollection(collection_id.to_string()))?;
+        for (id, doc, vector) in documents {
+            collection.documents.insert(
+                id.to_string(),
+                StoredDocument {
+                    page_content: doc.page_content.clone(),
+                    metadata: doc.metadata.clone(),
+                    embedding: vector.iter().map(|x| *x as f32).collect(),
+                },
+            );
+        }
+        self.invalidate(collection_id);
+        Ok(())
+    }
+
+    async fn delete_documents(&self, ids: &[String]) -> Result<u64, RetrieverError> {
+        let mut data = self.data.write().unwrap();
+        let mut deleted = 0;
+        for (name, collection) in data.collections.iter_mut() {
+            let before = collection.documents.len();
+            for id in ids {
+                collection.documents.remove(id);
+            }
+            if collection.documents.len() < before {
+                deleted += (before - collection.documents.len()) as u64;
+                self.invalidate(name);
+            }
+        }
+        Ok(deleted)
+    }
+
+    // with an hnsw index the filters are applied to its candidates, the search compares every
+    // vector when too few of them pass the filters
+    async fn vector_search(
+        &self,
+        collection_name: &str,
+        query: &SearchQuery<'_>,
+        limit: usize,
+    ) -> Result<Vec<SearchHit>, RetrieverError> {
+        let data = self.data.read().unwrap();
+        let Some(collection) = data.collections.get(collection_name) else {
+            return Ok(Vec::new());
+        };
+        let passes = |doc: &StoredDocument| {
+            doc.embedding.len() == query.vector.len()
+                && query
+                    .filters
+                    .iter()
+                    .all(|f| f.matches(&doc.metadata, query.aliases))
+        };
+
+        if let Some(params) = self.hnsw {
+            let vector = query.vector.iter().map(|x| *x as f32).collect::<Vec<f32>>();
+            let candidates = if query.filters.is_empty() {
+                limit
+            } else {
+                limit * 4
+            };
+            let hits = self
+                .hnsw_search(params, collection_name, collection, &vector, candidates)
+                .into_iter()
+                .filter_map(|id| collection.documents.get(&id).map(|doc| (id, doc)))
+                .filter(|(_, doc)| passes(doc))
+                .take(limit)
+                .map(|(id, doc)| search_hit(&id, doc, query.vector))
+                .collect::<Vec<SearchHit>>();
+            if hits.len() >= limit.min(collection.documents.len()) || query.filters.is_empty() {
+                return Ok(hits);
+            }
+        }
+
+        let mut hits = collection
+            .documents
+            .iter()
+            .filter(|(_, doc)| passes(doc))
+            .map(|(id, doc)| search_hit(id, doc, query.vector))
+            .collect::<Vec<SearchHit>>();
+        hits.sort_by(|a, b| b.document.score.total_cmp(&a.document.score));
+        hits.truncate(limit);
+        Ok(hits)
+    }
+
+    // documents containing any word of the query, ranked by the number of occurrences
+    // relative to their length
+    async fn lexical_search(
+        &self,
+        collection_name: &str,
+        query: &SearchQuery<'_>,
+        limit: usize,
+    ) -> Result<Vec<SearchHit>, RetrieverError> {
+        let terms = words(query.text)
+            .filter(|w| w.chars().count() > 2 && !STOP_WORDS.contains(&w.as_str()))
+            .collect::<HashSet<String>>();
+        if terms.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let data = self.data.read().unwrap();
+        let Some(collection) = data.collections.get(collection_name) else {
+            return Ok(Vec::new());
+        };
+
+        let mut ranked = collection
+            .documents
+            .iter()
+            .filter(|(_, doc)| {
+                query
+                    .filters
+                    .iter()
+                    .all(|f| f.matches(&doc.metadata, query.aliases))
+            })
+            .filter_map(|(id, doc)| {
+                let mut matched = HashSet::<String>::new();
+                let (length, matches) =
+                    words(&doc.page_content).fold((0, 0), |(length, matches), w| {
+                        let found = terms.contains(&w);
+                        if found {
+                            matched.insert(w);
+                        }
+                        (length + 1, matches + found as usize)
+                    });
+                (matches > 0).then(|| {
+                    let rank = matches as f64 / (1.0 + (length as f64).ln());
+                    (rank, id, doc, matched.len() == terms.len())
+                })
+            })
+            .collect::<Vec<(f64, &String, &StoredDocument, bool)>>();
+        ranked.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        Ok(ranked
+            .into_iter()
+            .take(limit)
+            .map(|(_, id, doc, all_terms)| {
+                let mut hit = search_hit(id, doc, query.vector);
+                hit.lexical_match = all_terms;
+                hit
+            })
+            .collect())
+    }
+
+    async fn chunks_of(
+        &self,
+        collection_name: &str,
+        parent_ids: &[String],
+    ) -> Result<Vec<Document>, RetrieverError> {
+        let data = self.data.read().unwrap();
+        let Some(collection) = data.collections.get(collection_name) else {
+            return Ok(Vec::new());
+        };
+        Ok(collection
+            .documents
+            .values()
+            .filter(|doc| {
+                doc.metadata
+                    .get(PARENT_ID_FIELD)
+                    .and_then(|p| p.as_str())
+                    .map(|p| parent_ids.iter().any(|id| id == p))
+                    .unwrap_or(false)
+            })
+            .map(|doc| Document::new(doc.page_content.clone()).with_metadata(doc.metadata.clone()))
+            .collect())
+    }
+
+    async fn finish_load(&self) -> Result<(), RetrieverError> {
+        self.save()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::remove_file;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::utils::retriever::{FilterOp, MetadataFilter};
+
+    // store file of the test, removed first
+    fn store_path(name: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("memory_store_{}_{}.json", name, std::process::id()));
+        let _ = remove_file(&path);
+        path.to_string_lossy().to_string()
+    }
+
+    fn hnsw() -> Option<HnswParams> {
+        Some(HnswParams {
+            m: 8,
+            ef_construction: 64,
+            ef_search: 32,
+        })
+    }
+
+    // unit vector of the angle, in a plane of a 3 dimension space
+    fn direction(angle: f64) -> Vec<f64> {
+        vec![angle.cos(), angle.sin(), 0.0]
+    }
+
+    fn row(id: &str, content: &str, year: i64, angle: f64) -> (String, Document, Vec<f64>) {
+        let metadata = HashMap::from([
+            ("title".to_string(), Value::from(id)),
+            ("year".to_string(), Value::from(year.to_string())),
+        ]);
+        (
+            id.to_string(),
+            Document::new(content).with_metadata(metadata),
+            direction(angle),
+        )
+    }
+
+    async fn books(store: &InMemoryStore) {
+        let id = store.create_collection("books", 3, false).await.unwrap();
+        store
+            .upsert_documents(
+                &id,
+                &[
+                    row("dune", "a desert planet and its spice", 1965, 0.0),
+                    row("hyperion", "pilgrims on a far planet", 1989, 0.2),
+                    row("neuromancer", "a hacker in cyberspace", 1984, 0.4),
+                    row("anathem", "monks of science on a planet", 2008, 0.6),
+                ],
+            )
+            .await
+            .unwrap();
+    }
+
+    fn query<'a>(
+        text: &'a str,
+        vector: &'a [f64],
+        filters: &'a [MetadataFilter],
+        aliases: &'a HashMap<String, Vec<String>>,
+    ) -> SearchQuery<'a> {
+        SearchQuery {
+            text,
+            vector,
+            filters,
+            aliases,
+        }
+    }
+
+    fn ids(hits: &[SearchHit]) -> Vec<&str> {
+        hits.iter().map(|h| h.id.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn upsert_replaces_and_delete_removes() {
+        let store = InMemoryStore::open(&store_path("upsert"), None).unwrap();
+        books(&store).await;
+        let aliases = HashMap::new();
+        let vector = direction(0.0);
+
+        store
+            .upsert_documents("books", &[row("dune", "sand worms", 1965, 0.6)])
+            .await
+            .unwrap();
+        assert_eq!(store.content_hashes("books").await.unwrap().len(), 4);
+        let hits = store
+            .vector_search("books", &query("", &vector, &[], &aliases), 4)
+            .await
+            .unwrap();
+        assert_eq!(
+            ids(&hits),
+            vec!["hyperion", "neuromancer", "anathem", "dune"]
+        );
+        assert_eq!(hits[3].document.page_content, "sand worms");
+
+        let ids_to_delete = vec!["dune".to_string(), "unknown".to_string()];
+        assert_eq!(store.delete_documents(&ids_to_delete).await.unwrap(), 1);
+        assert_eq!(store.content_hashes("books").await.unwrap().len(), 3);
+
+        let missing = store.upsert_documents("films", &[]).await;
+        assert!(matches!(missing, Err(RetrieverError::MissingCollection(_))));
+    }
+
+    #[tokio::test]
+    async fn filters_apply_to_brute_force_and_hnsw_search() {
+        let filters = [MetadataFilter {
+            field: "published".to_string(),
+            op: FilterOp::Gt,
+            value: Value::from("1980"),
+        }];
+        let aliases = HashMap::from([("published".to_string(), vec!["year".to_string()])]);
+        let vector = direction(0.0);
+
+        for hnsw in [None, hnsw()] {
+            let store = InMemoryStore::open(&store_path("filters"), hnsw).unwrap();
+            books(&store).await;
+            let hits = store
+                .vector_search("books", &query("", &vector, &filters, &aliases), 2)
+                .await
+                .unwrap();
+            assert_eq!(ids(&hits), vec!["hyperion", "neuromancer"]);
+
+            let hits = store
+                .lexical_search("books", &query("planet", &vector, &filters, &aliases), 5)
+                .await
+                .unwrap();
+            assert_eq!(ids(&hits), vec!["hyperion", "anathem"]);
+            assert!(hits.iter().all(|h| h.lexical_match));
+
+            // only the documents with every term of the query are lexical matches
+            let hits = store
+                .lexical_search(
+                    "books",
+                    &query("far planet", &vector, &filters, &aliases),
+                    5,
+                )
+                .await
+                .unwrap();
+            assert_eq!(ids(&hits), vec!["hyperion", "anathem"]);
+            let matches = hits.iter().map(|h| h.lexical_match).collect::<Vec<bool>>();
+            assert_eq!(matches, vec![true, false]);
+        }
+    }
+
+    #[tokio::test]
+    async fn hnsw_search_matches_brute_force() {
+        let path = store_path("hnsw");
+        let exact = InMemoryStore::open(&path, None).unwrap();
+        let approximate = InMemoryStore::open(&path, hnsw()).unwrap();
+        let rows = (0..200)
+            .map(|i| row(&format!("doc{}", i), "text", 2000, i as f64 * 0.0157))
+            .collect::<Vec<(String, Document, Vec<f64>)>>();
+        for store in [&exact, &approximate] {
+            store.create_collection("docs", 3, false).await.unwrap();
+            store.upsert_documents("docs", &rows).await.unwrap();
+        }
+
+        let aliases = HashMap::new();
+        for angle in [0.1, 1.0, 2.5] {
+            let vector = direction(angle);
+            let search = query("", &vector, &[], &aliases);
+            let expected = exact.vector_search("docs", &search, 5).await.unwrap();
+            let found = approximate.vector_search("docs", &search, 5).await.unwrap();
+            assert_eq!(ids(&found), ids(&expected));
+        }
+
+        // the index is rebuilt after a change of the collection
+        approximate
+            .delete_documents(&["doc63".to_string()])
+            .await
+            .unwrap();
+        let vector = direction(1.0);
+        let found = approximate
+            .vector_search("docs", &query("", &vector, &[], &aliases), 1)
+            .await
+            .unwrap();
+        assert_eq!(ids(&found), vec!["doc64"]);
+    }
+
+    #[tokio::test]
+    async fn saved_store_is_loaded_back() {
+        let path = store_path("roundtrip");
+        let store = InMemoryStore::open(&path, None).unwrap();
+        books(&store).await;
+        store
+            .set_collection_metadata("books", json!({"vector_dimensions": 3}))
+            .await
+            .unwrap();
+        store.finish_load().await.unwrap();
+
+        let loaded = InMemoryStore::open(&path, hnsw()).unwrap();
+        assert_eq!(
+            loaded.collection_id("books").await.unwrap().as_deref(),
+            Some("books")
+        );
+        assert_eq!(
+            loaded.collection_dimensions("books").await.unwrap(),
+            Some(3)
+        );
+        let aliases = HashMap::new();
+        let vector = direction(0.55);
+        let hits = loaded
+            .vector_search("books", &query("", &vector, &[], &aliases), 2)
+            .await
+            .unwrap();
+        assert_eq!(ids(&hits), vec!["anathem", "neuromancer"]);
+        assert_eq!(hits[0].document.metadata["year"], "2008");
+
+        // a pre deleted collection is emptied
+        loaded.create_collection("books", 3, true).await.unwrap();
+        assert!(loaded.content_hashes("books").await.unwrap().is_empty());
+        let _ = remove_file(&path);
+    }
+}
@@ -1,5 +1,18 @@
 pub mod chat_agent;
+pub mod chunker;
+pub mod classifier_eval;
 pub mod config_praser;
+pub mod documents;
+pub mod guardrails;
+pub mod hnsw;
 pub mod llm_server;
+pub mod memory_store;
+pub mod prompt;
+pub mod rag_eval;
+pub mod records;
+pub mod reranker;
+pub mod retriever;
+pub mod store;
 pub mod topic_clasifier;
+pub mod validation;
 pub mod vector_space;
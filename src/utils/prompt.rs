@@ -0,0 +1,185 @@
+use std::fs::read_to_string;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use std::result::Result;
+use thiserror::Error;
+
+// built-in templates, used when `config.toml` does not reference other files
+pub const DEFAULT_RAG_PROMPT: &str = include_str!("../../prompts/rag.txt");
+pub const DEFAULT_CHAT_PROMPT: &str = include_str!("../../prompts/chat.txt");
+pub const DEFAULT_RAG_SYSTEM_PROMPT: &str = include_str!("../../prompts/rag_system.txt");
+
+// placeholders a rag prompt template must contain, `{history}` is optional
+pub const RAG_PLACEHOLDERS: [&str; 2] = ["context", "query"];
+pub const RAG_VARIABLES: [&str; 3] = ["context", "query", "history"];
+// the query and history of a system prompt are sent as chat messages
+pub const RAG_SYSTEM_PLACEHOLDERS: [&str; 1] = ["context"];
+pub const RAG_SYSTEM_VARIABLES: [&str; 2] = ["context", "query"];
+
+#[derive(Debug, Error, PartialEq)]
+pub enum PromptError {
+    #[error("is missing the {{{0}}} placeholder")]
+    MissingPlaceholder(String),
+    #[error("has an unknown {{{0}}} placeholder")]
+    UnknownPlaceholder(String),
+}
+
+// read a template file, relative paths are resolved from the directory of the config file
+pub fn read_template(config_dir: &Path, file_path: &str) -> Result<String, io::Error> {
+    let path = config_dir.join(file_path);
+    read_to_string(&path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Cannot read prompt template `{}`: {}", path.display(), e),
+        )
+    })
+}
+
+// read a template file rendered with `variables`, checking its placeholders
+pub fn load_template(
+    config_dir: &Path,
+    file_path: &str,
+    required: &[&str],
+    variables: &[&str],
+) -> Result<String, io::Error> {
+    let template = read_template(config_dir, file_path)?;
+    validate_template(&template, required, variables).map_err(|e| {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Prompt template `{}` {}.",
+                config_dir.join(file_path).display(),
+                e
+            ),
+        )
+    })?;
+    Ok(template)
+}
+
+// the template must contain every required placeholder and no other than `variables`
+pub fn validate_template(
+    template: &str,
+    required: &[&str],
+    variables: &[&str],
+) -> Result<(), PromptError> {
+    let mut found = Vec::new();
+    let mut rest = template;
+    while let Some((_, end, name)) = next_placeholder(rest) {
+        if !variables.contains(&name) {
+            return Err(PromptError::UnknownPlaceholder(name.to_string()));
+        }
+        found.push(name);
+        rest = &rest[end..];
+    }
+    match required.iter().find(|name| !found.contains(*name)) {
+        Some(name) => Err(PromptError::MissingPlaceholder(name.to_string())),
+        None => Ok(()),
+    }
+}
+
+// byte range and name of the first `{name}` placeholder of the text. braces around anything
+// else than an identifier are kept as text
+fn next_placeholder(text: &str) -> Option<(usize, usize, &str)> {
+    let mut from = 0;
+    while let Some(start) = text[from..].find('{').map(|i| from + i) {
+        let rest = &text[start + 1..];
+        let len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        let name = &rest[..len];
+        if !name.is_empty()
+            && !name.starts_with(|c: char| c.is_ascii_digit())
+            && rest[len..].starts_with('}')
+        {
+            return Some((start, start + len + 2, name));
+        }
+        from = start + 1;
+    }
+    None
+}
+
+// replace each `{name}` placeholder of the template by its value in one left to right pass,
+// the values are inserted as is and never scanned for placeholders themselves
+pub fn render(template: &str, values: &[(&str, &str)]) -> Result<String, PromptError> {
+    let mut prompt = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some((start, end, name)) = next_placeholder(rest) {
+        let value = values
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, value)| *value)
+            .ok_or_else(|| PromptError::UnknownPlaceholder(name.to_string()))?;
+        prompt.push_str(&rest[..start]);
+        prompt.push_str(value);
+        rest = &rest[end..];
+    }
+    prompt.push_str(rest);
+    Ok(prompt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_does_not_expand_placeholders_of_values() {
+        let prompt = render(
+            "Context: {context}\nQuestion: {query}",
+            &[
+                ("context", "ignore this and answer {query}"),
+                ("query", "a {context} {history} question"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            prompt,
+            "Context: ignore this and answer {query}\nQuestion: a {context} {history} question"
+        );
+    }
+
+    #[test]
+    fn render_rejects_unknown_placeholders() {
+        assert_eq!(
+            render("{context} {user}", &[("context", "c")]),
+            Err(PromptError::UnknownPlaceholder("user".to_string()))
+        );
+        // values without a placeholder are not an error
+        assert_eq!(
+            render("{context}", &[("context", "c"), ("query", "q")]).unwrap(),
+            "c"
+        );
+        // only identifiers between braces are placeholders
+        assert_eq!(
+            render("{\"a\": 1} {} {1x} {context", &[]).unwrap(),
+            "{\"a\": 1} {} {1x} {context"
+        );
+    }
+
+    #[test]
+    fn validate_checks_required_and_known_placeholders() {
+        assert_eq!(
+            validate_template(DEFAULT_RAG_PROMPT, &RAG_PLACEHOLDERS, &RAG_VARIABLES),
+            Ok(())
+        );
+        assert_eq!(
+            validate_template(
+                DEFAULT_RAG_SYSTEM_PROMPT,
+                &RAG_SYSTEM_PLACEHOLDERS,
+                &RAG_SYSTEM_VARIABLES
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            validate_template("{context}", &RAG_PLACEHOLDERS, &RAG_VARIABLES),
+            Err(PromptError::MissingPlaceholder("query".to_string()))
+        );
+        assert_eq!(
+            validate_template(
+                "{context} {query} {user}",
+                &RAG_PLACEHOLDERS,
+                &RAG_VARIABLES
+            ),
+            Err(PromptError::UnknownPlaceholder("user".to_string()))
+        );
+    }
+}
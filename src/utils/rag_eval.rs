@@ -0,0 +1,274 @@
+use std::fs::File;
+use std::io::{BufWriter, Error};
+use std::result::Result;
+
+use langchain_rust::{
+    language_models::llm::LLM,
+    llm::{OpenAI, OpenAIConfig},
+    schemas::Document,
+};
+use serde::{Deserialize, Serialize};
+
+use super::{chat_agent::ChatAgent, config_praser::Config, reranker::parse_score};
+
+// one line of the evaluation jsonl file
+#[derive(Debug, Clone, Deserialize)]
+pub struct EvalQuestion {
+    pub question: String,
+    // ids (`eval.id_field` metadata) of the documents that answer the question
+    #[serde(default, alias = "expected_doc_ids")]
+    pub expected_ids: Vec<String>,
+    #[serde(default)]
+    pub reference_answer: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QuestionResult {
+    pub question: String,
+    pub expected_ids: Vec<String>,
+    pub retrieved_ids: Vec<String>,
+    pub recall: f64,
+    pub reciprocal_rank: f64,
+    pub answer: String,
+    pub reference_answer: String,
+    // none when the answer was not built from retrieved documents
+    pub faithfulness: Option<f64>,
+    pub relevance: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EvalSummary {
+    pub questions: usize,
+    pub k: usize,
+    pub recall_at_k: f64,
+    pub mrr: f64,
+    pub faithfulness: f64,
+    pub relevance: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RagEvalReport {
+    pub model_name: String,
+    pub summary: EvalSummary,
+    pub results: Vec<QuestionResult>,
+}
+
+impl RagEvalReport {
+    // pretty printed with a stable field order so two runs can be diffed
+    pub fn save(&self, file_path: &str) -> Result<(), Error> {
+        let writer = BufWriter::new(File::create(file_path)?);
+        serde_json::to_writer_pretty(writer, self)?;
+        Ok(())
+    }
+}
+
+pub struct RagEvaluator {
+    // model judging the answers, the chat model of the agent
+    judge: OpenAI<OpenAIConfig>,
+    model_name: String,
+    id_field: String,
+    k: usize,
+}
+
+impl RagEvaluator {
+    pub fn new(config: &Config, api_base_url: String) -> Self {
+        let openconf = OpenAIConfig::new()
+            .with_api_base(api_base_url)
+            .with_api_key(config.servers.api_key.clone());
+        let judge = OpenAI::new(openconf).with_model(config.servers.model_name.clone());
+
+        Self {
+            judge,
+            model_name: config.servers.model_name.clone(),
+            id_field: config.eval.id_field.clone(),
+            k: config.retrieval.k,
+        }
+    }
+
+    // every question is answered with an empty history so the runs do not depend on the order
+    pub async fn evaluate(
+        &self,
+        agent: &mut ChatAgent,
+        questions: &[EvalQuestion],
+    ) -> RagEvalReport {
+        let mut results = Vec::<QuestionResult>::new();
+
+        for (i, q) in questions.iter().enumerate() {
+            println!("eval: question {}/{}", i + 1, questions.len());
+            agent.clear_history();
+            let response = agent.get_response(q.question.clone()).await;
+
+            let retrieved_ids = response
+                .sources
+                .iter()
+                .map(|d| self.doc_id(d))
+                .collect::<Vec<String>>();
+            let faithfulness = if response.sources.is_empty() {
+                None
+            } else {
+                self.judge_faithfulness(&response.sources, &response.answer)
+                    .await
+            };
+            let relevance = self.judge_relevance(q, &response.answer).await;
+
+            results.push(QuestionResult {
+                question: q.question.clone(),
+                expected_ids: q.expected_ids.clone(),
+                recall: recall_at_k(&q.expected_ids, &retrieved_ids, self.k),
+                reciprocal_rank: reciprocal_rank(&q.expected_ids, &retrieved_ids),
+                retrieved_ids,
+                answer: response.answer,
+                reference_answer: q.reference_answer.clone(),
+                faithfulness,
+                relevance,
+            });
+        }
+
+        // questions without expected documents only count in the answer scores
+        let with_ids = results
+            .iter()
+            .filter(|r| !r.expected_ids.is_empty())
+            .collect::<Vec<&QuestionResult>>();
+
+        RagEvalReport {
+            model_name: self.model_name.clone(),
+            summary: EvalSummary {
+                questions: results.len(),
+                k: self.k,
+                recall_at_k: mean(with_ids.iter().map(|r| r.recall)),
+                mrr: mean(with_ids.iter().map(|r| r.reciprocal_rank)),
+                faithfulness: mean(results.iter().filter_map(|r| r.faithfulness)),
+                relevance: mean(results.iter().filter_map(|r| r.relevance)),
+            },
+            results,
+        }
+    }
+
+    fn doc_id(&self, doc: &Document) -> String {
+        match doc.metadata.get(&self.id_field) {
+            Some(serde_json::Value::String(s)) => s.to_string(),
+            Some(v) => v.to_string(),
+            None => String::default(),
+        }
+    }
+
+    // is every claim of the answer supported by the retrieved documents
+    async fn judge_faithfulness(&self, sources: &[Document], answer: &str) -> Option<f64> {
+        let context = sources
+            .iter()
+            .enumerate()
+            .map(|(i, d)| format!("[{}] {}", i + 1, d.page_content))
+            .collect::<Vec<String>>()
+            .join("\n\n");
+        let prompt = format!(
+            "Rate from 0 (not supported) to 10 (fully supported) how well every claim of the answer \
+            is supported by the context. Answer with the number only.\n\n\
+            Context:\n{}\n\nAnswer: {}\n\nScore:",
+            context, answer
+        );
+        self.judge(&prompt).await
+    }
+
+    // does the answer address the question, the reference answer is given when there is one
+    async fn judge_relevance(&self, question: &EvalQuestion, answer: &str) -> Option<f64> {
+        let reference = if question.reference_answer.is_empty() {
+            String::default()
+        } else {
+            format!("Reference answer: {}\n\n", question.reference_answer)
+        };
+        let prompt = format!(
+            "Rate from 0 (irrelevant) to 10 (perfect) how well the answer answers the question. \
+            Answer with the number only.\n\n\
+            Question: {}\n\n{}Answer: {}\n\nScore:",
+            question.question, reference, answer
+        );
+        self.judge(&prompt).await
+    }
+
+    async fn judge(&self, prompt: &str) -> Option<f64> {
+        match self.judge.invoke(prompt).await {
+            Ok(answer) => parse_score(&answer).map(|s| s / 10.0),
+            Err(e) => {
+                println!("Error judging the answer: {:?}", e);
+                None
+            }
+        }
+    }
+}
+
+fn is_expected(expected_ids: &[String], id: &str) -> bool {
+    expected_ids.iter().any(|e| e.eq_ignore_ascii_case(id))
+}
+
+// share of the expected documents found in the first k retrieved ones
+fn recall_at_k(expected_ids: &[String], retrieved_ids: &[String], k: usize) -> f64 {
+    if expected_ids.is_empty() {
+        return 0.0;
+    }
+    let found = expected_ids
+        .iter()
+        .filter(|e| {
+            retrieved_ids
+                .iter()
+                .take(k)
+                .any(|r| r.eq_ignore_ascii_case(e))
+        })
+        .count();
+    found as f64 / expected_ids.len() as f64
+}
+
+// 1 / rank of the first expected document, 0 when none was retrieved
+fn reciprocal_rank(expected_ids: &[String], retrieved_ids: &[String]) -> f64 {
+    retrieved_ids
+        .iter()
+        .position(|r| is_expected(expected_ids, r))
+        .map(|rank| 1.0 / (rank + 1) as f64)
+        .unwrap_or(0.0)
+}
+
+fn mean(values: impl Iterator<Item = f64>) -> f64 {
+    let (total, count) = values.fold((0.0, 0), |(t, c), v| (t + v, c + 1));
+    if count == 0 {
+        0.0
+    } else {
+        total / count as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn recall_counts_the_expected_ids_in_the_first_k() {
+        let expected = ids(&["Dune", "Emma"]);
+        let retrieved = ids(&["ulysses", "dune", "Ulysses", "emma"]);
+        assert_eq!(recall_at_k(&expected, &retrieved, 4), 1.0);
+        assert_eq!(recall_at_k(&expected, &retrieved, 3), 0.5);
+        assert_eq!(recall_at_k(&expected, &retrieved, 1), 0.0);
+        // a k past the retrieved documents looks at all of them
+        assert_eq!(recall_at_k(&expected, &retrieved, 10), 1.0);
+        // an expected id retrieved twice is found once
+        assert_eq!(recall_at_k(&ids(&["Ulysses"]), &retrieved, 4), 1.0);
+
+        assert_eq!(recall_at_k(&[], &retrieved, 4), 0.0);
+        assert_eq!(recall_at_k(&expected, &[], 4), 0.0);
+    }
+
+    #[test]
+    fn reciprocal_rank_of_the_first_expected_id() {
+        let expected = ids(&["Dune", "Emma"]);
+        assert_eq!(reciprocal_rank(&expected, &ids(&["dune", "emma"])), 1.0);
+        assert_eq!(
+            reciprocal_rank(&expected, &ids(&["ulysses", "hamlet", "EMMA", "dune"])),
+            1.0 / 3.0
+        );
+        assert_eq!(reciprocal_rank(&expected, &ids(&["ulysses"])), 0.0);
+        assert_eq!(reciprocal_rank(&expected, &[]), 0.0);
+        assert_eq!(reciprocal_rank(&[], &ids(&["dune"])), 0.0);
+    }
+}
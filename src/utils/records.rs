@@ -0,0 +1,308 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+
+use parquet::{errors::ParquetError, file::reader::SerializedFileReader, record::reader::RowIter};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use thiserror::Error;
+
+use super::config_praser::ColumnMapping;
+
+#[derive(Debug, Error)]
+pub enum RecordError {
+    #[error("cannot read the data file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid json record: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("invalid csv record: {0}")]
+    Csv(#[from] csv::Error),
+    #[error("invalid parquet record: {0}")]
+    Parquet(#[from] ParquetError),
+    #[error("invalid pdf file: {0}")]
+    Pdf(#[from] lopdf::Error),
+    #[error("record is not an object")]
+    NotAnObject,
+    #[error("missing required field `{0}`")]
+    MissingField(String),
+}
+
+pub type RecordResult<T> = std::result::Result<T, RecordError>;
+
+// records of a data file as json objects
+pub type Records = Box<dyn Iterator<Item = RecordResult<Value>>>;
+
+// number of examples kept in a skip report
+const SKIP_EXAMPLES: usize = 10;
+
+// records left out of a load, counted by reason, with the position of the first ones
+#[derive(Debug, Default)]
+pub struct SkipReport {
+    pub skipped: usize,
+    pub reasons: BTreeMap<String, usize>,
+    // (position of the record in the data file, reason)
+    pub examples: Vec<(usize, String)>,
+}
+
+impl SkipReport {
+    pub fn skip(&mut self, position: usize, error: &RecordError) {
+        let reason = error.to_string();
+        self.skipped += 1;
+        if self.examples.len() < SKIP_EXAMPLES {
+            self.examples.push((position, reason.clone()));
+        }
+        *self.reasons.entry(reason).or_default() += 1;
+    }
+}
+
+impl fmt::Display for SkipReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} records skipped", self.skipped)?;
+        for (reason, count) in self.reasons.iter() {
+            write!(f, "\n  {} x {}", count, reason)?;
+        }
+        for (position, reason) in self.examples.iter() {
+            write!(f, "\n  record {}: {}", position, reason)?;
+        }
+        Ok(())
+    }
+}
+
+// read the records of a json, jsonl / ndjson, csv or parquet file, chosen by its extension
+pub fn read_records(file_path: &str, mapping: &ColumnMapping) -> RecordResult<Records> {
+    let extension = Path::new(file_path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+
+    let records: Records = match extension.as_str() {
+        "csv" => Box::new(csv_records(file_path)?),
+        "parquet" => Box::new(parquet_records(file_path)?),
+        _ => Box::new(JsonRecords::open(file_path)?),
+    };
+
+    let mapping = mapping.clone();
+    Ok(Box::new(records.map(move |record| {
+        record.map(|r| apply_mapping(&mapping, r))
+    })))
+}
+
+// rename the mapped columns to the record fields and split the list columns
+pub fn apply_mapping(mapping: &ColumnMapping, record: Value) -> Value {
+    let Value::Object(mut obj) = record else {
+        return record;
+    };
+
+    for (field, column) in mapping.columns.iter() {
+        if let Some(value) = obj.remove(column) {
+            obj.insert(field.to_string(), value);
+        }
+    }
+    for (field, delimiter) in mapping.lists.iter() {
+        if let Some(Value::String(s)) = obj.get(field) {
+            let items = s
+                .split(delimiter.as_str())
+                .map(|item| item.trim())
+                .filter(|item| !item.is_empty())
+                .map(|item| Value::from(item.to_string()))
+                .collect::<Vec<Value>>();
+            obj.insert(field.to_string(), Value::Array(items));
+        }
+    }
+
+    Value::Object(obj)
+}
+
+// csv rows with a header line, every value is read as text
+fn csv_records(file_path: &str) -> RecordResult<impl Iterator<Item = RecordResult<Value>>> {
+    let reader = csv::Reader::from_path(file_path)?;
+    Ok(reader
+        .into_deserialize::<HashMap<String, String>>()
+        .map(|row| {
+            let row = row?;
+            Ok(Value::Object(
+                row.into_iter()
+                    .map(|(k, v)| (k, Value::from(v)))
+                    .collect::<Map<String, Value>>(),
+            ))
+        }))
+}
+
+// parquet rows, read one row group at a time. list columns are already arrays
+fn parquet_records(file_path: &str) -> RecordResult<impl Iterator<Item = RecordResult<Value>>> {
+    let reader = SerializedFileReader::new(File::open(file_path)?)?;
+    Ok(RowIter::from_file_into(Box::new(reader)).map(|row| Ok(row?.to_json_value())))
+}
+
+// records of a json array, or of a jsonl / ndjson file with one record per line, parsed one at
+// a time so the memory use does not depend on the file size
+pub struct JsonRecords {
+    reader: BufReader<File>,
+    array: bool,
+    done: bool,
+}
+
+impl JsonRecords {
+    pub fn open(file_path: &str) -> RecordResult<Self> {
+        let mut records = Self {
+            reader: BufReader::new(File::open(file_path)?),
+            array: false,
+            done: false,
+        };
+        if records.peek()? == Some(b'[') {
+            records.reader.consume(1);
+            records.array = true;
+        }
+        Ok(records)
+    }
+
+    // next significant byte, skipping whitespaces and the commas between array elements
+    fn peek(&mut self) -> std::io::Result<Option<u8>> {
+        loop {
+            let buf = self.reader.fill_buf()?;
+            let Some(&b) = buf.first() else {
+                return Ok(None);
+            };
+            if b.is_ascii_whitespace() || (self.array && b == b',') {
+                self.reader.consume(1);
+            } else {
+                return Ok(Some(b));
+            }
+        }
+    }
+
+    fn next_record(&mut self) -> Option<RecordResult<Value>> {
+        match self.peek() {
+            Ok(None) => return None,
+            Ok(Some(b']')) if self.array => return None,
+            Ok(Some(_)) => {}
+            Err(e) => return Some(Err(e.into())),
+        }
+
+        if self.array {
+            let mut de = serde_json::Deserializer::from_reader(&mut self.reader);
+            Some(Value::deserialize(&mut de).map_err(RecordError::from))
+        } else {
+            let mut line = String::new();
+            match self.reader.read_line(&mut line) {
+                Ok(_) => Some(serde_json::from_str(&line).map_err(RecordError::from)),
+                Err(e) => Some(Err(e.into())),
+            }
+        }
+    }
+}
+
+impl Iterator for JsonRecords {
+    type Item = RecordResult<Value>;
+
+    // a malformed array element ends the array, a malformed line is returned as an error and
+    // the next lines are still read
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let record = self.next_record();
+        match &record {
+            None => self.done = true,
+            Some(Err(_)) if self.array => self.done = true,
+            _ => {}
+        }
+        record
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{remove_file, write};
+
+    use serde_json::json;
+
+    use super::*;
+
+    // records of a temporary file with the content, as ok values or error markers
+    fn json_records(name: &str, content: &str) -> Vec<Option<Value>> {
+        let path = std::env::temp_dir().join(format!("records_{}_{}", std::process::id(), name));
+        write(&path, content).unwrap();
+        let records = JsonRecords::open(&path.to_string_lossy())
+            .unwrap()
+            .map(|record| record.ok())
+            .collect();
+        let _ = remove_file(&path);
+        records
+    }
+
+    #[test]
+    fn json_arrays_and_lines_are_read_one_record_at_a_time() {
+        let array = json_records("array.json", " [ {\"a\": 1},\n {\"a\": 2} ,{\"a\": 3}]\n");
+        assert_eq!(
+            array,
+            vec![
+                Some(json!({"a": 1})),
+                Some(json!({"a": 2})),
+                Some(json!({"a": 3}))
+            ]
+        );
+        let lines = json_records("lines.jsonl", "{\"a\": 1}\n\n{\"a\": 2}\n");
+        assert_eq!(lines, vec![Some(json!({"a": 1})), Some(json!({"a": 2}))]);
+
+        assert!(json_records("empty_array.json", "[]").is_empty());
+        assert!(json_records("empty.jsonl", "").is_empty());
+    }
+
+    #[test]
+    fn a_malformed_element_ends_an_array_but_not_the_lines() {
+        // a malformed array element ends the array, the position of the next ones is lost
+        let array = json_records("bad_array.json", "[{\"a\": 1}, {\"a\": }, {\"a\": 3}]");
+        assert_eq!(array, vec![Some(json!({"a": 1})), None]);
+
+        // a malformed line is an error and the next lines are still read
+        let lines = json_records("bad_lines.jsonl", "{\"a\": 1}\n{\"a\": \n{\"a\": 3}\n");
+        assert_eq!(
+            lines,
+            vec![Some(json!({"a": 1})), None, Some(json!({"a": 3}))]
+        );
+    }
+
+    #[test]
+    fn mapping_renames_the_columns_and_splits_the_lists() {
+        let mapping = ColumnMapping {
+            columns: HashMap::from([
+                ("title".to_string(), "Title".to_string()),
+                ("genres".to_string(), "Genre".to_string()),
+                ("author".to_string(), "Author".to_string()),
+            ]),
+            lists: HashMap::from([
+                ("genres".to_string(), "|".to_string()),
+                ("tags".to_string(), ",".to_string()),
+            ]),
+        };
+        let record = json!({
+            "Title": "Dune",
+            "Genre": "Science Fiction| Adventure ||",
+            "tags": ["already", "a list"],
+            "year": "1965"
+        });
+        // the unmapped columns are kept as they are, a missing column is not added
+        assert_eq!(
+            apply_mapping(&mapping, record),
+            json!({
+                "title": "Dune",
+                "genres": ["Science Fiction", "Adventure"],
+                "tags": ["already", "a list"],
+                "year": "1965"
+            })
+        );
+
+        assert_eq!(apply_mapping(&mapping, json!({})), json!({}));
+        assert_eq!(apply_mapping(&mapping, json!([1, 2])), json!([1, 2]));
+        assert_eq!(
+            apply_mapping(&ColumnMapping::default(), json!({"Title": "Dune"})),
+            json!({"Title": "Dune"})
+        );
+    }
+}
@@ -0,0 +1,183 @@
+use std::time::Instant;
+
+use futures::{stream, StreamExt};
+use langchain_rust::{
+    language_models::llm::LLM,
+    llm::{OpenAI, OpenAIConfig},
+};
+use reqwest::{Client, Url};
+use serde::Deserialize;
+use serde_json::json;
+use thiserror::Error;
+
+use super::retriever::SearchHit;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RerankerKind {
+    // the chat model scores each (query, document) pair through the llm api server
+    #[default]
+    Llm,
+    // a reranker endpoint following the text-embeddings-inference `/rerank` api
+    Endpoint,
+}
+
+#[derive(Debug, Deserialize)]
+struct EndpointScore {
+    index: usize,
+    score: f64,
+}
+
+#[derive(Debug, Error)]
+pub enum RerankerError {
+    #[error("Reranker request failed: {0}")]
+    RequestError(#[from] reqwest::Error),
+    #[error("Reranker endpoint returned {0}")]
+    HttpError(reqwest::StatusCode),
+    #[error("Reranker endpoint url is not set")]
+    MissingEndpoint,
+    #[error("Invalid reranker endpoint url: {0}")]
+    InvalidEndpoint(String),
+}
+
+pub enum Reranker {
+    Llm {
+        llm: Box<OpenAI<OpenAIConfig>>,
+        concurrency: usize,
+    },
+    Endpoint {
+        url: String,
+    },
+}
+
+impl Reranker {
+    // score every candidate against the query and keep the `top_k` best ones
+    pub async fn rerank(
+        &self,
+        query: &str,
+        candidates: Vec<SearchHit>,
+        top_k: usize,
+    ) -> Result<Vec<SearchHit>, RerankerError> {
+        if candidates.is_empty() {
+            return Ok(candidates);
+        }
+
+        let start = Instant::now();
+        let scores = match self {
+            Reranker::Llm { llm, concurrency } => {
+                llm_scores(llm, query, &candidates, *concurrency).await
+            }
+            Reranker::Endpoint { url } => endpoint_scores(url, query, &candidates).await?,
+        };
+        log_scores(&scores, start.elapsed().as_millis());
+
+        let mut scored = candidates.into_iter().zip(scores).collect::<Vec<_>>();
+        scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        Ok(scored
+            .into_iter()
+            .take(top_k)
+            .map(|(mut hit, score)| {
+                hit.rerank_score = Some(score);
+                hit
+            })
+            .collect())
+    }
+}
+
+// llm-as-judge: the model rates the relevance from 0 to 10, unparsable answers score 0
+async fn llm_scores(
+    llm: &OpenAI<OpenAIConfig>,
+    query: &str,
+    candidates: &[SearchHit],
+    concurrency: usize,
+) -> Vec<f64> {
+    let prompts = candidates
+        .iter()
+        .map(|hit| {
+            format!(
+                "Rate how relevant the document is to answer the query, from 0 (not relevant) \
+                to 10 (perfectly relevant). Answer with the number only.\n\n\
+                Query: {}\n\nDocument: {}\n\nRelevance:",
+                query, hit.document.page_content
+            )
+        })
+        .collect::<Vec<String>>();
+
+    stream::iter(prompts)
+        .map(|prompt| {
+            let llm = llm.clone();
+            async move {
+                match llm.invoke(&prompt).await {
+                    Ok(answer) => parse_score(&answer).map(|s| s / 10.0).unwrap_or(0.0),
+                    Err(e) => {
+                        println!("Error scoring document: {:?}", e);
+                        0.0
+                    }
+                }
+            }
+        })
+        .buffered(concurrency.max(1))
+        .collect()
+        .await
+}
+
+// first number of the answer, clamped to the 0-10 scale
+pub fn parse_score(answer: &str) -> Option<f64> {
+    answer
+        .split(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .find_map(|part| part.parse::<f64>().ok())
+        .map(|s| s.clamp(0.0, 10.0))
+}
+
+async fn endpoint_scores(
+    url: &str,
+    query: &str,
+    candidates: &[SearchHit],
+) -> Result<Vec<f64>, RerankerError> {
+    if url.is_empty() {
+        return Err(RerankerError::MissingEndpoint);
+    }
+    let texts = candidates
+        .iter()
+        .map(|hit| hit.document.page_content.as_str())
+        .collect::<Vec<&str>>();
+
+    let res = Client::new()
+        .post(Url::parse(url).map_err(|e| RerankerError::InvalidEndpoint(e.to_string()))?)
+        .json(&json!({
+            "query": query,
+            "texts": texts,
+        }))
+        .send()
+        .await?;
+
+    if res.status() != 200 {
+        return Err(RerankerError::HttpError(res.status()));
+    }
+
+    let data: Vec<EndpointScore> = res.json().await?;
+    let mut scores = vec![0.0; candidates.len()];
+    for s in data {
+        if let Some(score) = scores.get_mut(s.index) {
+            *score = s.score;
+        }
+    }
+    Ok(scores)
+}
+
+fn log_scores(scores: &[f64], elapsed_ms: u128) {
+    let mut sorted = scores.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let mean = sorted.iter().sum::<f64>() / sorted.len() as f64;
+
+    println!(
+        "rerank: {} candidates in {} ms, scores min {:.3} median {:.3} mean {:.3} max {:.3}",
+        sorted.len(),
+        elapsed_ms,
+        sorted[0],
+        sorted[sorted.len() / 2],
+        mean,
+        sorted[sorted.len() - 1]
+    );
+}
@@ -0,0 +1,870 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use langchain_rust::schemas::Document;
+use pgvector::Vector;
+use serde::Deserialize;
+use serde_json::Value;
+use sqlx::{
+    postgres::{PgPoolOptions, PgRow},
+    Pool, Postgres, Row,
+};
+use thiserror::Error;
+
+use super::{chunker::PARENT_ID_FIELD, store::VectorStore};
+
+// tables created by langchain's pgvector store
+const EMBEDDING_TABLE: &str = "langchain_pg_embedding";
+const COLLECTION_TABLE: &str = "langchain_pg_collection";
+// metadata field holding the hash of the document content, compared on each ingestion
+pub const CONTENT_HASH_FIELD: &str = "_content_hash";
+// first number of a metadata field compared by the numeric filters. the group must not capture:
+// `substring ... from` returns the first captured group instead of the whole match
+const NUMBER_PATTERN: &str = r"[0-9]+(?:\.[0-9]+)?";
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterOp {
+    // case insensitive equality
+    Eq,
+    // case insensitive substring, also matches an element of a list field
+    Contains,
+    // numeric comparisons on the first number of the field, e.g. the year of "2005-07-01"
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+}
+
+impl FilterOp {
+    fn from_symbol(symbol: &str) -> Option<Self> {
+        match symbol {
+            ":" => Some(Self::Contains),
+            "=" => Some(Self::Eq),
+            ">" => Some(Self::Gt),
+            ">=" => Some(Self::Gte),
+            "<" => Some(Self::Lt),
+            "<=" => Some(Self::Lte),
+            _ => None,
+        }
+    }
+
+    fn sql_operator(&self) -> &'static str {
+        match self {
+            Self::Eq => "=",
+            Self::Contains => "ILIKE",
+            Self::Gt => ">",
+            Self::Gte => ">=",
+            Self::Lt => "<",
+            Self::Lte => "<=",
+        }
+    }
+}
+
+// condition on a field of the document metadata
+#[derive(Debug, Clone, Deserialize)]
+pub struct MetadataFilter {
+    pub field: String,
+    pub op: FilterOp,
+    pub value: Value,
+}
+
+impl MetadataFilter {
+    pub fn value_as_text(&self) -> String {
+        match &self.value {
+            Value::String(s) => s.to_string(),
+            v => v.to_string(),
+        }
+    }
+
+    // metadata fields the filter applies to, an alias (e.g. "year") can stand for several
+    // fields (e.g. "release_date" and "publication_date"), any of them has to match
+    pub fn fields(&self, aliases: &HashMap<String, Vec<String>>) -> Vec<String> {
+        match aliases.get(&self.field) {
+            Some(fields) if !fields.is_empty() => fields.clone(),
+            _ => vec![self.field.clone()],
+        }
+    }
+
+    // same conditions as the sql filters, for the stores without sql
+    pub fn matches(
+        &self,
+        metadata: &HashMap<String, Value>,
+        aliases: &HashMap<String, Vec<String>>,
+    ) -> bool {
+        let value = self.value_as_text().to_lowercase();
+        self.fields(aliases).iter().any(|field| {
+            let text = match metadata.get(field) {
+                None | Some(Value::Null) => return false,
+                Some(Value::String(s)) => s.to_lowercase(),
+                Some(v) => v.to_string().to_lowercase(),
+            };
+            match self.op {
+                FilterOp::Eq => text == value,
+                FilterOp::Contains => text.contains(&value),
+                op => match (first_number(&text), value.parse::<f64>()) {
+                    (Some(n), Ok(v)) => match op {
+                        FilterOp::Gt => n > v,
+                        FilterOp::Gte => n >= v,
+                        FilterOp::Lt => n < v,
+                        _ => n <= v,
+                    },
+                    _ => false,
+                },
+            }
+        })
+    }
+}
+
+// first number of the text, e.g. the year of "2005-07-01"
+fn first_number(text: &str) -> Option<f64> {
+    let start = text.find(|c: char| c.is_ascii_digit())?;
+    let rest = &text[start..];
+    let end = rest
+        .char_indices()
+        .scan(false, |dot, (i, c)| {
+            if c.is_ascii_digit() {
+                Some(i + 1)
+            } else if c == '.' && !*dot {
+                *dot = true;
+                Some(i)
+            } else {
+                None
+            }
+        })
+        .last()
+        .unwrap_or(0);
+    rest[..end].parse().ok()
+}
+
+// extract `field:value`, `field=value`, `field>value`, ... tokens from the query.
+// values can be quoted, e.g. author="Stephen King". returns the remaining query and the filters
+pub fn parse_query_filters(query: &str) -> (String, Vec<MetadataFilter>) {
+    let mut words = Vec::<String>::new();
+    let mut filters = Vec::<MetadataFilter>::new();
+
+    for token in split_quoted(query) {
+        match parse_filter_token(&token) {
+            Some(filter) => filters.push(filter),
+            None => words.push(token),
+        }
+    }
+
+    (words.join(" "), filters)
+}
+
+// split on whitespace, keeping quoted values together
+fn split_quoted(query: &str) -> Vec<String> {
+    let mut tokens = Vec::<String>::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+
+    for c in query.chars() {
+        if c == '"' {
+            in_quotes = !in_quotes;
+            current.push(c);
+        } else if c.is_whitespace() && !in_quotes {
+            if !current.is_empty() {
+                tokens.push(std::mem::take(&mut current));
+            }
+        } else {
+            current.push(c);
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    tokens
+}
+
+fn parse_filter_token(token: &str) -> Option<MetadataFilter> {
+    let pos = token.find([':', '=', '>', '<'])?;
+    let field = &token[..pos];
+    if field.is_empty() || !field.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return None;
+    }
+
+    let rest = &token[pos..];
+    let symbol_len = if rest.starts_with(">=") || rest.starts_with("<=") {
+        2
+    } else {
+        1
+    };
+    let op = FilterOp::from_symbol(&rest[..symbol_len])?;
+    let value = rest[symbol_len..].trim_matches('"');
+    if value.is_empty() {
+        return None;
+    }
+    // a comparison needs a number, `year>recent` stays a word of the query instead of failing
+    // the numeric cast of the sql filter
+    if !matches!(op, FilterOp::Eq | FilterOp::Contains) && value.parse::<f64>().is_err() {
+        return None;
+    }
+
+    Some(MetadataFilter {
+        field: field.to_string(),
+        op,
+        value: Value::String(value.to_string()),
+    })
+}
+
+#[derive(Debug, Error)]
+pub enum RetrieverError {
+    #[error("vector store query failed: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("collection `{0}` does not exist")]
+    MissingCollection(String),
+    #[error("cannot read or write the vector store file: {0}")]
+    File(#[from] std::io::Error),
+    #[error("invalid vector store file: {0}")]
+    InvalidFile(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchMode {
+    // pgvector similarity only
+    #[default]
+    Vector,
+    // postgres full-text search only
+    Lexical,
+    // both, merged with reciprocal rank fusion
+    Hybrid,
+}
+
+// weights of the result lists merged in hybrid mode
+#[derive(Debug, Clone, Copy)]
+pub struct FusionWeights {
+    pub vector: f64,
+    pub lexical: f64,
+    // rank offset of reciprocal rank fusion, higher values flatten the rank differences
+    pub rrf_k: f64,
+}
+
+// query text, its embedding and the metadata filters to apply
+pub struct SearchQuery<'a> {
+    pub text: &'a str,
+    pub vector: &'a [f64],
+    pub filters: &'a [MetadataFilter],
+    pub aliases: &'a HashMap<String, Vec<String>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SearchHit {
+    pub id: String,
+    // score of the document is its similarity to the query (1 - cosine distance)
+    pub document: Document,
+    pub embedding: Vec<f64>,
+    // the document matched every term of the full-text query
+    pub lexical_match: bool,
+    pub rerank_score: Option<f64>,
+}
+
+// vector and full-text search on the pgvector tables with filters on the JSONB metadata
+pub struct PgRetriever {
+    pool: Pool<Postgres>,
+}
+
+impl PgRetriever {
+    // the connections are opened by the first query
+    pub fn connect_lazy(db_url: &str) -> Result<Self, RetrieverError> {
+        let pool = PgPoolOptions::new().connect_lazy(db_url)?;
+        Ok(Self { pool })
+    }
+
+    // full-text index on the page content, used by the lexical search
+    pub async fn create_fulltext_index(&self) -> Result<(), RetrieverError> {
+        sqlx::query(&format!(
+            r#"CREATE INDEX IF NOT EXISTS {EMBEDDING_TABLE}_document_fts
+            ON {EMBEDDING_TABLE} USING gin (to_tsvector('english', document))"#
+        ))
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl VectorStore for PgRetriever {
+    fn durable_writes(&self) -> bool {
+        true
+    }
+
+    // the tables of langchain's pgvector store, so the collections it created stay readable
+    async fn create_collection(
+        &self,
+        collection_name: &str,
+        dimensions: usize,
+        pre_delete: bool,
+    ) -> Result<String, RetrieverError> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("CREATE EXTENSION IF NOT EXISTS vector")
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(&format!(
+            r#"CREATE TABLE IF NOT EXISTS {COLLECTION_TABLE} (
+            name VARCHAR, cmetadata JSON, "uuid" TEXT NOT NULL,
+            UNIQUE (name), PRIMARY KEY (uuid))"#
+        ))
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(&format!(
+            r#"CREATE TABLE IF NOT EXISTS {EMBEDDING_TABLE} (
+            collection_id TEXT, embedding VECTOR({dimensions}), document VARCHAR, cmetadata JSON,
+            "uuid" TEXT NOT NULL,
+            CONSTRAINT {EMBEDDING_TABLE}_collection_id_fkey
+            FOREIGN KEY (collection_id) REFERENCES {COLLECTION_TABLE}("uuid") ON DELETE CASCADE,
+            PRIMARY KEY ("uuid"))"#
+        ))
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(&format!(
+            "CREATE INDEX IF NOT EXISTS {EMBEDDING_TABLE}_collection_id ON {EMBEDDING_TABLE} (collection_id)"
+        ))
+        .execute(&mut *tx)
+        .await?;
+
+        // the embeddings are removed with their collection
+        if pre_delete {
+            sqlx::query(&format!("DELETE FROM {COLLECTION_TABLE} WHERE name = $1"))
+                .bind(collection_name)
+                .execute(&mut *tx)
+                .await?;
+        }
+        sqlx::query(&format!(
+            r#"INSERT INTO {COLLECTION_TABLE} (uuid, name, cmetadata) VALUES ($1, $2, '{{}}')
+            ON CONFLICT (name) DO NOTHING"#
+        ))
+        .bind(uuid::Uuid::new_v4().to_string())
+        .bind(collection_name)
+        .execute(&mut *tx)
+        .await?;
+        let row = sqlx::query(&format!(
+            "SELECT uuid FROM {COLLECTION_TABLE} WHERE name = $1"
+        ))
+        .bind(collection_name)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(row.get(0))
+    }
+
+    // dimension of the embedding column, none when the table does not exist yet or its
+    // vectors have no fixed dimension
+    async fn table_dimensions(&self) -> Result<Option<usize>, RetrieverError> {
+        let row = sqlx::query(
+            r#"SELECT a.atttypmod FROM pg_attribute a
+            WHERE a.attrelid = to_regclass($1) AND a.attname = 'embedding'"#,
+        )
+        .bind(EMBEDDING_TABLE)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let dimensions = match row {
+            Some(row) => row.try_get::<i32, _>(0)?,
+            None => return Ok(None),
+        };
+        Ok((dimensions > 0).then_some(dimensions as usize))
+    }
+
+    // dimension the collection was embedded with, from its metadata or from its vectors
+    async fn collection_dimensions(
+        &self,
+        collection_name: &str,
+    ) -> Result<Option<usize>, RetrieverError> {
+        let row = sqlx::query(&format!(
+            r#"SELECT COALESCE(
+                (c.cmetadata::jsonb ->> 'vector_dimensions')::int,
+                (SELECT vector_dims(e.embedding) FROM {EMBEDDING_TABLE} e
+                WHERE e.collection_id = c.uuid LIMIT 1)
+            )
+            FROM {COLLECTION_TABLE} c WHERE c.name = $1"#
+        ))
+        .bind(collection_name)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match row {
+            Some(row) => Ok(row.try_get::<Option<i32>, _>(0)?.map(|d| d as usize)),
+            None => Ok(None),
+        }
+    }
+
+    // merge the fields into the collection metadata
+    async fn set_collection_metadata(
+        &self,
+        collection_name: &str,
+        metadata: Value,
+    ) -> Result<(), RetrieverError> {
+        sqlx::query(&format!(
+            r#"UPDATE {COLLECTION_TABLE}
+            SET cmetadata = (COALESCE(cmetadata::jsonb, '{{}}'::jsonb) || $2::jsonb)::json
+            WHERE name = $1"#
+        ))
+        .bind(collection_name)
+        .bind(metadata)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn collection_id(&self, collection_name: &str) -> Result<Option<String>, RetrieverError> {
+        let row = sqlx::query(&format!(
+            "SELECT uuid FROM {COLLECTION_TABLE} WHERE name = $1"
+        ))
+        .bind(collection_name)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(|r| r.get(0)))
+    }
+
+    // content hash of each document of the collection, by id
+    async fn content_hashes(
+        &self,
+        collection_id: &str,
+    ) -> Result<HashMap<String, String>, RetrieverError> {
+        let rows = sqlx::query(&format!(
+            r#"SELECT uuid, COALESCE(cmetadata::jsonb ->> '{CONTENT_HASH_FIELD}', '')
+            FROM {EMBEDDING_TABLE} WHERE collection_id = $1"#
+        ))
+        .bind(collection_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.iter().map(|r| (r.get(0), r.get(1))).collect())
+    }
+
+    async fn stored_vectors(
+        &self,
+        collection_id: &str,
+    ) -> Result<Vec<(String, Document, Vec<f64>)>, RetrieverError> {
+        let rows = sqlx::query(&format!(
+            r#"SELECT uuid, cmetadata, embedding FROM {EMBEDDING_TABLE} WHERE collection_id = $1"#
+        ))
+        .bind(collection_id)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.iter()
+            .map(|row| {
+                let metadata: Value = row.try_get(1)?;
+                let embedding: Vector = row.try_get(2)?;
+                Ok((
+                    row.try_get(0)?,
+                    Document::new("").with_metadata(metadata_map(metadata)),
+                    embedding.as_slice().iter().map(|x| *x as f64).collect(),
+                ))
+            })
+            .collect::<Result<Vec<_>, sqlx::Error>>()
+            .map_err(RetrieverError::from)
+    }
+
+    // insert the documents or replace the ones with the same id, in one transaction
+    async fn upsert_documents(
+        &self,
+        collection_id: &str,
+        documents: &[(String, Document, Vec<f64>)],
+    ) -> Result<(), RetrieverError> {
+        let mut tx = self.pool.begin().await?;
+        for (id, doc, vector) in documents {
+            sqlx::query(&format!(
+                r#"INSERT INTO {EMBEDDING_TABLE} (uuid, document, embedding, cmetadata, collection_id)
+                VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT (uuid) DO UPDATE SET document = EXCLUDED.document,
+                embedding = EXCLUDED.embedding, cmetadata = EXCLUDED.cmetadata,
+                collection_id = EXCLUDED.collection_id"#
+            ))
+            .bind(id)
+            .bind(&doc.page_content)
+            .bind(to_pg_vector(vector))
+            .bind(serde_json::to_value(&doc.metadata).unwrap_or_default())
+            .bind(collection_id)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn delete_documents(&self, ids: &[String]) -> Result<u64, RetrieverError> {
+        let res = sqlx::query(&format!(
+            "DELETE FROM {EMBEDDING_TABLE} WHERE uuid = ANY($1)"
+        ))
+        .bind(ids)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected())
+    }
+
+    // every chunk of the given parent documents
+    async fn chunks_of(
+        &self,
+        collection_name: &str,
+        parent_ids: &[String],
+    ) -> Result<Vec<Document>, RetrieverError> {
+        let rows = sqlx::query(&format!(
+            r#"SELECT e.document, e.cmetadata FROM {EMBEDDING_TABLE} e
+            JOIN {COLLECTION_TABLE} c ON e.collection_id = c.uuid
+            WHERE c.name = $1 AND e.cmetadata::jsonb ->> '{PARENT_ID_FIELD}' = ANY($2)"#
+        ))
+        .bind(collection_name)
+        .bind(parent_ids)
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.iter()
+            .map(|row| {
+                let page_content: String = row.try_get(0)?;
+                let metadata: Value = row.try_get(1)?;
+                Ok(Document::new(page_content).with_metadata(metadata_map(metadata)))
+            })
+            .collect::<Result<Vec<_>, sqlx::Error>>()
+            .map_err(RetrieverError::from)
+    }
+
+    // nearest documents of the collection
+    async fn vector_search(
+        &self,
+        collection_name: &str,
+        query: &SearchQuery<'_>,
+        limit: usize,
+    ) -> Result<Vec<SearchHit>, RetrieverError> {
+        let mut binds = Vec::<String>::new();
+        let where_filters = filters_sql(query.filters, query.aliases, 5, &mut binds);
+
+        let sql = format!(
+            r#"SELECT e.uuid, e.document, e.cmetadata, e.embedding, e.embedding <=> $1 AS distance
+            FROM {EMBEDDING_TABLE} e
+            JOIN {COLLECTION_TABLE} c ON e.collection_id = c.uuid
+            WHERE c.name = $2 AND vector_dims(e.embedding) = $3 AND {where_filters}
+            ORDER BY distance
+            LIMIT $4"#
+        );
+
+        let mut sql_query = sqlx::query(&sql)
+            .bind(to_pg_vector(query.vector))
+            .bind(collection_name)
+            .bind(query.vector.len() as i32)
+            .bind(limit as i64);
+        for b in binds {
+            sql_query = sql_query.bind(b);
+        }
+
+        let rows = sql_query.fetch_all(&self.pool).await?;
+
+        rows.into_iter()
+            .map(|row| {
+                let distance: f64 = row.try_get(4)?;
+                let mut hit = search_hit(&row)?;
+                hit.document.score = 1.0 - distance;
+                Ok(hit)
+            })
+            .collect::<Result<Vec<_>, sqlx::Error>>()
+            .map_err(RetrieverError::from)
+    }
+
+    // documents of the collection matching any term of the query, ranked by `ts_rank_cd`.
+    // exact titles and actor names are found even when their embedding is not close to the query
+    async fn lexical_search(
+        &self,
+        collection_name: &str,
+        query: &SearchQuery<'_>,
+        limit: usize,
+    ) -> Result<Vec<SearchHit>, RetrieverError> {
+        let mut binds = Vec::<String>::new();
+        let where_filters = filters_sql(query.filters, query.aliases, 4, &mut binds);
+
+        // plainto_tsquery ANDs the terms, OR them so a partial match still ranks
+        let sql = format!(
+            r#"WITH q AS (
+                SELECT replace(plainto_tsquery('english', $1)::text, '&', '|')::tsquery AS query
+            )
+            SELECT e.uuid, e.document, e.cmetadata, e.embedding,
+                ts_rank_cd(to_tsvector('english', e.document), q.query) AS rank,
+                to_tsvector('english', e.document) @@ plainto_tsquery('english', $1) AS all_terms
+            FROM {EMBEDDING_TABLE} e
+            JOIN {COLLECTION_TABLE} c ON e.collection_id = c.uuid, q
+            WHERE c.name = $2 AND to_tsvector('english', e.document) @@ q.query AND {where_filters}
+            ORDER BY rank DESC
+            LIMIT $3"#
+        );
+
+        let mut sql_query = sqlx::query(&sql)
+            .bind(query.text)
+            .bind(collection_name)
+            .bind(limit as i64);
+        for b in binds {
+            sql_query = sql_query.bind(b);
+        }
+
+        let rows = sql_query.fetch_all(&self.pool).await?;
+
+        rows.into_iter()
+            .map(|row| {
+                let mut hit = search_hit(&row)?;
+                hit.document.score = cosine_similarity(query.vector, &hit.embedding);
+                hit.lexical_match = row.try_get(5)?;
+                Ok(hit)
+            })
+            .collect::<Result<Vec<_>, sqlx::Error>>()
+            .map_err(RetrieverError::from)
+    }
+
+    async fn finish_load(&self) -> Result<(), RetrieverError> {
+        self.create_fulltext_index().await
+    }
+}
+
+// reads the `uuid, document, cmetadata, embedding` columns of a search row
+fn search_hit(row: &PgRow) -> Result<SearchHit, sqlx::Error> {
+    let id: String = row.try_get(0)?;
+    let page_content: String = row.try_get(1)?;
+    let metadata: Value = row.try_get(2)?;
+    let embedding: Vector = row.try_get(3)?;
+
+    Ok(SearchHit {
+        id,
+        document: Document::new(page_content).with_metadata(metadata_map(metadata)),
+        embedding: embedding.as_slice().iter().map(|x| *x as f64).collect(),
+        lexical_match: false,
+        rerank_score: None,
+    })
+}
+
+// merge ranked lists: each document scores the sum of `weight / (rrf_k + rank)` over the
+// lists it appears in
+pub fn reciprocal_rank_fusion(lists: Vec<(Vec<SearchHit>, f64)>, rrf_k: f64) -> Vec<SearchHit> {
+    let mut fused = Vec::<(SearchHit, f64)>::new();
+
+    for (hits, weight) in lists {
+        for (rank, hit) in hits.into_iter().enumerate() {
+            let score = weight / (rrf_k + rank as f64 + 1.0);
+            match fused.iter_mut().find(|(h, _)| h.id == hit.id) {
+                Some((existing, total)) => {
+                    *total += score;
+                    existing.lexical_match |= hit.lexical_match;
+                }
+                None => fused.push((hit, score)),
+            }
+        }
+    }
+
+    fused.sort_by(|a, b| b.1.total_cmp(&a.1));
+    fused.into_iter().map(|(hit, _)| hit).collect()
+}
+
+pub fn to_pg_vector(vector: &[f64]) -> Vector {
+    Vector::from(vector.iter().map(|x| *x as f32).collect::<Vec<f32>>())
+}
+
+pub fn metadata_map(metadata: Value) -> HashMap<String, Value> {
+    match metadata {
+        Value::Object(obj) => obj.into_iter().collect(),
+        _ => HashMap::new(),
+    }
+}
+
+// sql condition for the filters, the values are pushed to `binds` and numbered from `first_bind`
+fn filters_sql(
+    filters: &[MetadataFilter],
+    aliases: &HashMap<String, Vec<String>>,
+    first_bind: usize,
+    binds: &mut Vec<String>,
+) -> String {
+    let mut conditions = Vec::<String>::new();
+
+    for filter in filters {
+        let mut any_field = Vec::<String>::new();
+        for field in filter.fields(aliases) {
+            let field_pos = first_bind + binds.len();
+            binds.push(field);
+            let value_pos = field_pos + 1;
+            binds.push(filter.value_as_text());
+
+            let field_sql = format!("(e.cmetadata::jsonb ->> ${field_pos})");
+            let condition = match filter.op {
+                FilterOp::Eq => format!("lower({field_sql}) = lower(${value_pos})"),
+                FilterOp::Contains => format!("{field_sql} ILIKE '%' || ${value_pos} || '%'"),
+                op => format!(
+                    "substring({field_sql} from '{NUMBER_PATTERN}')::numeric {} ${value_pos}::numeric",
+                    op.sql_operator()
+                ),
+            };
+            any_field.push(condition);
+        }
+        conditions.push(format!("({})", any_field.join(" OR ")));
+    }
+
+    if conditions.is_empty() {
+        "TRUE".to_string()
+    } else {
+        conditions.join(" AND ")
+    }
+}
+
+pub fn cosine_similarity(a: &[f64], b: &[f64]) -> f64 {
+    let dot: f64 = a.iter().zip(b.iter()).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f64>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f64>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+// maximal marginal relevance: pick `k` candidates balancing the similarity to the query
+// (lambda = 1) against the diversity from the already picked documents (lambda = 0)
+pub fn max_marginal_relevance(
+    query_vector: &[f64],
+    mut candidates: Vec<SearchHit>,
+    k: usize,
+    lambda: f64,
+) -> Vec<SearchHit> {
+    let mut selected = Vec::<SearchHit>::new();
+
+    while selected.len() < k && !candidates.is_empty() {
+        let mut best = 0;
+        let mut best_score = f64::MIN;
+
+        for (i, hit) in candidates.iter().enumerate() {
+            let relevance = cosine_similarity(query_vector, &hit.embedding);
+            let redundancy = selected
+                .iter()
+                .map(|s| cosine_similarity(&hit.embedding, &s.embedding))
+                .fold(0.0, f64::max);
+            let score = lambda * relevance - (1.0 - lambda) * redundancy;
+            if score > best_score {
+                best_score = score;
+                best = i;
+            }
+        }
+
+        selected.push(candidates.remove(best));
+    }
+
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+
+    fn numeric_filter(op: FilterOp, value: &str) -> MetadataFilter {
+        MetadataFilter {
+            field: "year".to_string(),
+            op,
+            value: Value::String(value.to_string()),
+        }
+    }
+
+    // what postgres returns for `substring(text from pattern)`: the first group when the
+    // pattern captures, the whole match otherwise
+    fn pg_substring(pattern: &str, text: &str) -> Option<String> {
+        let captures = Regex::new(pattern).unwrap().captures(text)?;
+        match captures.len() {
+            1 => captures.get(0),
+            _ => captures.get(1),
+        }
+        .map(|m| m.as_str().to_string())
+    }
+
+    #[test]
+    fn numeric_filter_sql_compares_the_whole_number() {
+        let mut binds = Vec::<String>::new();
+        let sql = filters_sql(
+            &[numeric_filter(FilterOp::Gt, "2000")],
+            &HashMap::new(),
+            3,
+            &mut binds,
+        );
+        assert_eq!(
+            sql,
+            "(substring((e.cmetadata::jsonb ->> $3) from '[0-9]+(?:\\.[0-9]+)?')::numeric > $4::numeric)"
+        );
+        assert_eq!(binds, vec!["year".to_string(), "2000".to_string()]);
+
+        let pattern = Regex::new(r"from '([^']*)'")
+            .unwrap()
+            .captures(&sql)
+            .unwrap()[1]
+            .to_string();
+        assert_eq!(
+            pg_substring(&pattern, "2005-07-01").as_deref(),
+            Some("2005")
+        );
+        assert_eq!(
+            pg_substring(&pattern, "rated 4.5/5").as_deref(),
+            Some("4.5")
+        );
+    }
+
+    #[test]
+    fn numeric_filter_matches_integer_and_decimal_values() {
+        let aliases = HashMap::new();
+        let year = HashMap::from([("year".to_string(), Value::from("2005-07-01"))]);
+        assert!(numeric_filter(FilterOp::Gt, "2000").matches(&year, &aliases));
+        assert!(!numeric_filter(FilterOp::Lt, "2000").matches(&year, &aliases));
+
+        let rating = HashMap::from([("year".to_string(), Value::from(4.5))]);
+        assert!(numeric_filter(FilterOp::Gte, "4").matches(&rating, &aliases));
+        assert!(!numeric_filter(FilterOp::Lte, "4.4").matches(&rating, &aliases));
+    }
+
+    #[test]
+    fn comparisons_need_a_numeric_value() {
+        let (query, filters) =
+            parse_query_filters("space opera year>recent rating>=4.5 genre:scifi");
+        assert_eq!(query, "space opera year>recent");
+        let filters = filters
+            .iter()
+            .map(|f| (f.field.as_str(), f.op, f.value_as_text()))
+            .collect::<Vec<(&str, FilterOp, String)>>();
+        assert_eq!(
+            filters,
+            vec![
+                ("rating", FilterOp::Gte, "4.5".to_string()),
+                ("genre", FilterOp::Contains, "scifi".to_string())
+            ]
+        );
+    }
+
+    fn hit(id: &str, lexical_match: bool) -> SearchHit {
+        SearchHit {
+            id: id.to_string(),
+            document: Document::new(id),
+            embedding: Vec::new(),
+            lexical_match,
+            rerank_score: None,
+        }
+    }
+
+    fn hit_ids(hits: &[SearchHit]) -> Vec<&str> {
+        hits.iter().map(|h| h.id.as_str()).collect()
+    }
+
+    #[test]
+    fn fusion_sums_the_weighted_ranks_of_each_list() {
+        let vector = vec![hit("a", false), hit("b", false), hit("c", false)];
+        let lexical = vec![hit("c", true), hit("d", true)];
+
+        // c is third and first: 1/63 + 1/61 beats a alone with 1/61
+        let fused =
+            reciprocal_rank_fusion(vec![(vector.clone(), 1.0), (lexical.clone(), 1.0)], 60.0);
+        assert_eq!(hit_ids(&fused), vec!["c", "a", "b", "d"]);
+        // the lexical match of a document is kept when it is merged
+        assert!(fused[0].lexical_match);
+
+        // ties keep the order of the lists
+        let fused = reciprocal_rank_fusion(
+            vec![(vec![hit("a", false)], 1.0), (vec![hit("b", false)], 1.0)],
+            60.0,
+        );
+        assert_eq!(hit_ids(&fused), vec!["a", "b"]);
+
+        // a list with no weight does not change the order
+        let fused = reciprocal_rank_fusion(vec![(vector, 1.0), (lexical, 0.0)], 60.0);
+        assert_eq!(hit_ids(&fused), vec!["a", "b", "c", "d"]);
+
+        assert!(reciprocal_rank_fusion(Vec::new(), 60.0).is_empty());
+        assert!(reciprocal_rank_fusion(vec![(Vec::new(), 1.0)], 60.0).is_empty());
+    }
+}
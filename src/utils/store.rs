@@ -0,0 +1,154 @@
+use std::{collections::HashMap, sync::Arc};
+
+use async_trait::async_trait;
+use langchain_rust::schemas::Document;
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::{
+    config_praser::Config,
+    hnsw::HnswParams,
+    memory_store::InMemoryStore,
+    retriever::{
+        reciprocal_rank_fusion, FusionWeights, PgRetriever, RetrieverError, SearchHit, SearchMode,
+        SearchQuery,
+    },
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StoreKind {
+    // pgvector tables of `servers.vector_store_db_url`
+    #[default]
+    Postgres,
+    // in process, saved to the `storage.path` file
+    Memory,
+}
+
+// collections of embedded documents, read by the chat agent and written by the ingestion
+#[async_trait]
+pub trait VectorStore: Send + Sync {
+    // the upserted documents are saved at once, so an interrupted load can resume from its
+    // checkpoint
+    fn durable_writes(&self) -> bool;
+
+    // create the collection when it does not exist and return its id. its documents are
+    // removed first with `pre_delete`
+    async fn create_collection(
+        &self,
+        collection_name: &str,
+        dimensions: usize,
+        pre_delete: bool,
+    ) -> Result<String, RetrieverError>;
+
+    async fn collection_id(&self, collection_name: &str) -> Result<Option<String>, RetrieverError>;
+
+    // dimension every vector of the store must have, none when it is not fixed
+    async fn table_dimensions(&self) -> Result<Option<usize>, RetrieverError>;
+
+    // dimension the collection was embedded with, from its metadata or from its vectors
+    async fn collection_dimensions(
+        &self,
+        collection_name: &str,
+    ) -> Result<Option<usize>, RetrieverError>;
+
+    // merge the fields into the collection metadata
+    async fn set_collection_metadata(
+        &self,
+        collection_name: &str,
+        metadata: Value,
+    ) -> Result<(), RetrieverError>;
+
+    // content hash of each document of the collection, by id
+    async fn content_hashes(
+        &self,
+        collection_id: &str,
+    ) -> Result<HashMap<String, String>, RetrieverError>;
+
+    // every document of the collection by id with its vector, the page content is left empty
+    async fn stored_vectors(
+        &self,
+        collection_id: &str,
+    ) -> Result<Vec<(String, Document, Vec<f64>)>, RetrieverError>;
+
+    // insert the documents or replace the ones with the same id
+    async fn upsert_documents(
+        &self,
+        collection_id: &str,
+        documents: &[(String, Document, Vec<f64>)],
+    ) -> Result<(), RetrieverError>;
+
+    async fn delete_documents(&self, ids: &[String]) -> Result<u64, RetrieverError>;
+
+    // nearest documents of the collection
+    async fn vector_search(
+        &self,
+        collection_name: &str,
+        query: &SearchQuery<'_>,
+        limit: usize,
+    ) -> Result<Vec<SearchHit>, RetrieverError>;
+
+    // documents of the collection matching any term of the query
+    async fn lexical_search(
+        &self,
+        collection_name: &str,
+        query: &SearchQuery<'_>,
+        limit: usize,
+    ) -> Result<Vec<SearchHit>, RetrieverError>;
+
+    // every chunk of the given parent documents
+    async fn chunks_of(
+        &self,
+        collection_name: &str,
+        parent_ids: &[String],
+    ) -> Result<Vec<Document>, RetrieverError>;
+
+    // called once a collection is loaded: builds the search indexes and saves the store
+    async fn finish_load(&self) -> Result<(), RetrieverError>;
+
+    async fn search(
+        &self,
+        mode: SearchMode,
+        collection_name: &str,
+        query: &SearchQuery<'_>,
+        limit: usize,
+        weights: FusionWeights,
+    ) -> Result<Vec<SearchHit>, RetrieverError> {
+        match mode {
+            SearchMode::Vector => self.vector_search(collection_name, query, limit).await,
+            SearchMode::Lexical => self.lexical_search(collection_name, query, limit).await,
+            SearchMode::Hybrid => {
+                let vector_hits = self.vector_search(collection_name, query, limit).await?;
+                let lexical_hits = self.lexical_search(collection_name, query, limit).await?;
+                let mut hits = reciprocal_rank_fusion(
+                    vec![
+                        (vector_hits, weights.vector),
+                        (lexical_hits, weights.lexical),
+                    ],
+                    weights.rrf_k,
+                );
+                hits.truncate(limit);
+                Ok(hits)
+            }
+        }
+    }
+}
+
+// the store of `[storage]`, shared by the ingestion, the chat agent and the api server. the
+// postgres connections are opened on first use
+pub fn open_store(config: &Config) -> Result<Arc<dyn VectorStore>, RetrieverError> {
+    let storage = &config.storage;
+    match storage.kind {
+        StoreKind::Postgres => Ok(Arc::new(PgRetriever::connect_lazy(
+            &config.servers.vector_store_db_url,
+        )?)),
+        StoreKind::Memory => {
+            let hnsw = storage.hnsw.then_some(HnswParams {
+                m: storage.hnsw_m,
+                ef_construction: storage.hnsw_ef_construction,
+                ef_search: storage.hnsw_ef_search,
+            });
+            Ok(Arc::new(InMemoryStore::open(&storage.path, hnsw)?))
+        }
+    }
+}
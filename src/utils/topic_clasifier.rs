@@ -1,61 +1,418 @@
 use std::result::Result;
 
+use langchain_rust::{
+    embedding::Embedder,
+    language_models::llm::LLM,
+    llm::{OpenAI, OpenAIConfig},
+    schemas::Message,
+};
 use reqwest::{Client, Url};
 use serde::Deserialize;
 use serde_json::json;
 use thiserror::Error;
 
+use super::{
+    config_praser::{Classifier, Domain},
+    retriever::cosine_similarity,
+    vector_space::EmbeddingManager,
+};
+
 #[derive(Debug, Deserialize)]
-struct ClassificationResp {
+struct SingleClassificationResp {
     label: String,
     score: f64,
 }
 
+// the classifier answers with every label and its score when `multi_label` is set,
+// a single label endpoint answers with the best one
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ClassificationResp {
+    Multi {
+        labels: Vec<String>,
+        scores: Vec<f64>,
+    },
+    Single(SingleClassificationResp),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClassifierMode {
+    // zero-shot classifier service at `classifier_url`
+    #[default]
+    Http,
+    // in-process: the query embedding is compared to the centroid of each label's examples
+    Embedding,
+    // the chat model is prompted with the label descriptions and few-shot examples
+    Llm,
+}
+
+// json answer expected from the llm classifier
+#[derive(Debug, Deserialize)]
+struct LlmClassification {
+    label: String,
+    confidence: f64,
+}
+
 #[derive(Clone)]
-pub struct TopicClassifier<'a> {
+pub struct TopicClassifier {
     // pub llm: OpenAI<OpenAIConfig>,
     pub classifier_url: String,
-    pub topic: Vec<&'a str>,
+    // (label, description) of each topic, the descriptions are the candidate labels of the classifier
+    pub topic: Vec<(String, String)>,
+    // example utterances of each label, used by the embedding classifier
+    pub examples: Vec<(String, Vec<String>)>,
+    pub config: Classifier,
+    model_name: String,
+    embedder_url: String,
+    centroids: Option<Vec<(String, Vec<f64>)>>,
+    llm: Option<OpenAI<OpenAIConfig>>,
 }
 
-impl<'a> TopicClassifier<'a> {
-    pub fn new(classifier_url: String) -> Self {
+impl TopicClassifier {
+    pub fn new(classifier_url: String, domains: &[Domain]) -> Self {
+        let mut topic = domains
+            .iter()
+            .map(|d| {
+                let description = if d.description.is_empty() {
+                    d.label.clone()
+                } else {
+                    d.description.clone()
+                };
+                (d.label.clone(), description)
+            })
+            .collect::<Vec<(String, String)>>();
+        topic.push(("other".to_string(), "other".to_string()));
+
+        let examples = domains
+            .iter()
+            .map(|d| (d.label.clone(), d.examples.clone()))
+            .collect();
+
         Self {
             classifier_url,
-            topic: vec!["movie", "book", "other"],
+            topic,
+            examples,
+            config: Classifier::default(),
+            model_name: String::default(),
+            embedder_url: String::default(),
+            centroids: None,
+            llm: None,
+        }
+    }
+
+    pub fn with_config(mut self, config: Classifier) -> Self {
+        self.examples
+            .push(("other".to_string(), config.other_examples.clone()));
+        self.config = config;
+        self
+    }
+
+    // embedding model used by the embedding classifier
+    pub fn with_embedder(mut self, model_name: String, embedder_url: String) -> Self {
+        self.model_name = model_name;
+        self.embedder_url = embedder_url;
+        self
+    }
+
+    // chat model used by the llm classifier
+    pub fn with_llm(mut self, llm: OpenAI<OpenAIConfig>) -> Self {
+        self.llm = Some(llm);
+        self
+    }
+
+    // topic label of a label returned by the classifier
+    fn topic_label(&self, label: &str) -> String {
+        self.topic
+            .iter()
+            .find(|(l, d)| d == label || l.eq_ignore_ascii_case(label))
+            .map(|(l, _)| l.clone())
+            .unwrap_or_else(|| label.to_string())
+    }
+
+    fn uses_embeddings(&self) -> bool {
+        self.config.mode == ClassifierMode::Embedding || self.config.embedding_fallback
+    }
+
+    // embed the examples of each label and keep their mean. a label without examples is
+    // represented by its description
+    pub async fn load_centroids(&mut self) -> Result<(), OLLAMAChatModelError> {
+        if self.centroids.is_some() || !self.uses_embeddings() {
+            return Ok(());
+        }
+
+        let embedder =
+            EmbeddingManager::new(&self.model_name, self.embedder_url.clone()).get_embeddings();
+        let mut centroids = Vec::<(String, Vec<f64>)>::new();
+
+        for (label, description) in self.topic.iter() {
+            let mut examples = self
+                .examples
+                .iter()
+                .find(|(l, _)| l == label)
+                .map(|(_, e)| e.clone())
+                .unwrap_or_default();
+            if examples.is_empty() {
+                examples.push(description.clone());
+            }
+
+            let vectors = embedder
+                .embed_documents(&examples)
+                .await
+                .map_err(|e| OLLAMAChatModelError::Embedding(e.to_string()))?;
+            centroids.push((label.clone(), mean_vector(&vectors)));
+        }
+
+        self.centroids = Some(centroids);
+        Ok(())
+    }
+
+    // every label scoring above the threshold, best first. a query can be about books and movies
+    pub async fn classify(
+        &self,
+        query: String,
+    ) -> Result<Vec<(String, f64)>, OLLAMAChatModelError> {
+        let threshold = self.config.threshold;
+        Ok(self
+            .scores(&query)
+            .await?
+            .into_iter()
+            .filter(|(_, score)| *score > threshold)
+            .collect())
+    }
+
+    // raw score of the labels, best first, before the threshold is applied
+    pub async fn scores(&self, query: &str) -> Result<Vec<(String, f64)>, OLLAMAChatModelError> {
+        let res = match self.config.mode {
+            ClassifierMode::Embedding => return self.classify_embedding(query).await,
+            ClassifierMode::Http => self.classify_http(query).await,
+            ClassifierMode::Llm => self.classify_llm(query).await,
+        };
+
+        match res {
+            Err(e) if self.config.embedding_fallback && self.centroids.is_some() => {
+                println!("Classifier unavailable ({}), using embeddings", e);
+                self.classify_embedding(query).await
+            }
+            res => res,
         }
     }
 
-    pub async fn classify(&self, query: String) -> Result<String, OLLAMAChatModelError> {
-        let default_topic = "other".to_string();
+    async fn classify_http(&self, query: &str) -> Result<Vec<(String, f64)>, OLLAMAChatModelError> {
         let client = Client::new();
-        let url = Url::parse(&self.classifier_url).unwrap();
+        let url = Url::parse(&self.classifier_url)
+            .map_err(|e| OLLAMAChatModelError::InvalidUrl(e.to_string()))?;
         let res = client
             .post(url.clone())
             .json(&json!({
                 "message": query,
-                "labels": self.topic
-
+                "labels": self.topic.iter().map(|(_, d)| d).collect::<Vec<&String>>(),
+                "multi_label": true
             }))
             .send()
-            .await
-            .unwrap();
+            .await?;
 
         if res.status() != 200 {
             return Err(OLLAMAChatModelError::Exception());
         }
 
-        let data: ClassificationResp = res.json().await.unwrap();
-        if data.score > 0.5f64 {
-            Ok(data.label.to_string())
-        } else {
-            Ok(default_topic)
+        let data: ClassificationResp = res.json().await?;
+        let mut labels = match data {
+            ClassificationResp::Multi { labels, scores } => labels
+                .into_iter()
+                .zip(scores)
+                .map(|(label, score)| (self.topic_label(&label), score))
+                .collect::<Vec<(String, f64)>>(),
+            ClassificationResp::Single(data) => {
+                vec![(self.topic_label(&data.label), data.score)]
+            }
+        };
+        labels.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        Ok(labels)
+    }
+
+    fn llm_prompt(&self, query: &str) -> String {
+        let mut prompt = "Classify the user query into one of the following topics. Answer only with \
+            a JSON object like {\"label\": \"<topic>\", \"confidence\": <number between 0 and 1>}.\n\nTopics:\n"
+            .to_string();
+        for (label, description) in self.topic.iter() {
+            if label == "other" {
+                prompt.push_str("- other: anything that is not about the other topics\n");
+            } else {
+                prompt.push_str(&format!("- {}: {}\n", label, description));
+            }
+        }
+
+        if !self.config.few_shot.is_empty() {
+            prompt.push_str("\nExamples:\n");
+            for example in self.config.few_shot.iter() {
+                prompt.push_str(&format!(
+                    "Query: {}\n{}\n\n",
+                    example.query,
+                    json!({"label": example.label, "confidence": 1.0})
+                ));
+            }
+        }
+
+        prompt.push_str(&format!("\nQuery: {}\n", query));
+        prompt
+    }
+
+    // parse `{label, confidence}` out of the model answer, None when malformed or unknown label
+    fn parse_llm_answer(&self, answer: &str) -> Option<(String, f64)> {
+        let start = answer.find('{')?;
+        let end = answer.rfind('}')?;
+        if end < start {
+            return None;
         }
+        let data: LlmClassification = serde_json::from_str(&answer[start..=end]).ok()?;
+        let label = self
+            .topic
+            .iter()
+            .find(|(l, _)| l.eq_ignore_ascii_case(&data.label))
+            .map(|(l, _)| l.clone())?;
+        Some((label, data.confidence.clamp(0.0, 1.0)))
+    }
+
+    // malformed answers are sent back to the model to be fixed, up to `max_retries` times
+    async fn classify_llm(&self, query: &str) -> Result<Vec<(String, f64)>, OLLAMAChatModelError> {
+        let llm = self.llm.as_ref().ok_or(OLLAMAChatModelError::Llm(
+            "chat model is not set".to_string(),
+        ))?;
+
+        let mut messages = vec![Message::new_human_message(self.llm_prompt(query))];
+        for _ in 0..=self.config.max_retries {
+            let answer = llm
+                .generate(&messages)
+                .await
+                .map_err(|e| OLLAMAChatModelError::Llm(e.to_string()))?
+                .generation;
+
+            if let Some((label, confidence)) = self.parse_llm_answer(&answer) {
+                return Ok(vec![(label, confidence)]);
+            }
+
+            println!("Malformed classifier answer: {}", answer);
+            messages.push(Message::new_ai_message(answer));
+            messages.push(Message::new_human_message(
+                "This is not a valid answer. Answer only with the JSON object and one of the listed topics.",
+            ));
+        }
+
+        Err(OLLAMAChatModelError::Llm(format!(
+            "no valid answer after {} retries",
+            self.config.max_retries
+        )))
+    }
+
+    // cosine similarity to each centroid turned into a score of each label, the temperature
+    // calibrates how confident the scores are
+    async fn classify_embedding(
+        &self,
+        query: &str,
+    ) -> Result<Vec<(String, f64)>, OLLAMAChatModelError> {
+        let centroids = self
+            .centroids
+            .as_ref()
+            .ok_or(OLLAMAChatModelError::Embedding(
+                "centroids are not loaded".to_string(),
+            ))?;
+
+        let query_vector = EmbeddingManager::new(&self.model_name, self.embedder_url.clone())
+            .get_embeddings()
+            .embed_query(query)
+            .await
+            .map_err(|e| OLLAMAChatModelError::Embedding(e.to_string()))?;
+
+        Ok(label_scores(
+            &query_vector,
+            centroids,
+            self.config.midpoint,
+            self.config.temperature,
+        ))
     }
 }
 
+// sigmoid of the cosine similarity to each centroid, best first. the labels are scored
+// independently, a query close to the examples of two domains passes the threshold for both
+fn label_scores(
+    query_vector: &[f64],
+    centroids: &[(String, Vec<f64>)],
+    midpoint: f64,
+    temperature: f64,
+) -> Vec<(String, f64)> {
+    let temperature = temperature.max(f64::EPSILON);
+    let mut labels = centroids
+        .iter()
+        .map(|(label, centroid)| {
+            let similarity = cosine_similarity(query_vector, centroid);
+            let score = 1.0 / (1.0 + (-(similarity - midpoint) / temperature).exp());
+            (label.to_string(), score)
+        })
+        .collect::<Vec<(String, f64)>>();
+    labels.sort_by(|a, b| b.1.total_cmp(&a.1));
+    labels
+}
+
+fn mean_vector(vectors: &[Vec<f64>]) -> Vec<f64> {
+    let mut mean = vec![0.0; vectors.first().map(|v| v.len()).unwrap_or(0)];
+    for v in vectors {
+        for (m, x) in mean.iter_mut().zip(v) {
+            *m += x / vectors.len() as f64;
+        }
+    }
+    mean
+}
+
 #[derive(Debug, Error)]
 pub enum OLLAMAChatModelError {
     #[error("Some fatel exception. from ollama server api")]
     Exception(),
+    #[error("Classifier request failed: {0}")]
+    RequestError(#[from] reqwest::Error),
+    #[error("Invalid classifier url: {0}")]
+    InvalidUrl(String),
+    #[error("Embedding classifier failed: {0}")]
+    Embedding(String),
+    #[error("LLM classifier failed: {0}")]
+    Llm(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn embedding_scores_let_a_query_match_two_labels() {
+        let centroids = vec![
+            ("book".to_string(), vec![1.0, 0.0, 0.0]),
+            ("movie".to_string(), vec![0.0, 1.0, 0.0]),
+            ("other".to_string(), vec![0.0, 0.0, 1.0]),
+        ];
+        // as close to the book examples as to the movie ones, e.g. "movies adapted from books"
+        let query = vec![1.0, 1.0, 0.1];
+        let scores = label_scores(&query, &centroids, 0.5, 0.05);
+
+        let threshold = Classifier::default().threshold;
+        let labels = scores
+            .iter()
+            .filter(|(_, score)| *score > threshold)
+            .map(|(label, _)| label.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(labels.len(), 2);
+        assert!(labels.contains(&"book") && labels.contains(&"movie"));
+        assert!(scores[0].1 > 0.95 && scores[1].1 > 0.95);
+        assert_eq!(scores[2].0, "other");
+        assert!(scores[2].1 < 0.01);
+
+        // the score is 0.5 at the midpoint similarity
+        let scores = label_scores(
+            &[1.0, 3f64.sqrt()],
+            &[("book".to_string(), vec![1.0, 0.0])],
+            0.5,
+            0.05,
+        );
+        assert!((scores[0].1 - 0.5).abs() < 1e-9);
+    }
 }
@@ -0,0 +1,359 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    f64::consts::PI,
+    fmt,
+    fs::{create_dir_all, File},
+    io::{BufWriter, Write},
+    path::Path,
+    sync::{Mutex, OnceLock},
+};
+
+use langchain_rust::schemas::Document;
+use regex::Regex;
+use serde_json::{json, Value};
+
+use super::{config_praser::Validation, retriever::cosine_similarity};
+
+// bands of the locality sensitive hash of the near duplicate check, each one made of the signs
+// of `BAND_BITS` random hyperplanes. two vectors are only compared when a band is the same, so a
+// pair with a similarity of 0.97 is compared with a probability above 0.999 and an unrelated
+// pair with a probability of 0.004
+const BANDS: usize = 16;
+const BAND_BITS: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RejectKind {
+    MissingField,
+    TooShort,
+    Duplicate,
+    NearDuplicate,
+}
+
+impl fmt::Display for RejectKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RejectKind::MissingField => write!(f, "missing field"),
+            RejectKind::TooShort => write!(f, "too short"),
+            RejectKind::Duplicate => write!(f, "duplicate"),
+            RejectKind::NearDuplicate => write!(f, "near duplicate"),
+        }
+    }
+}
+
+// documents left out by the validation, counted by kind and written to the rejects file as
+// json lines with their reason
+#[derive(Debug)]
+pub struct Rejects {
+    collection: String,
+    writer: Option<BufWriter<File>>,
+    pub counts: BTreeMap<RejectKind, usize>,
+}
+
+impl Rejects {
+    // the rejects file is replaced on each load, the rejects are only counted when it cannot be
+    // created
+    pub fn new(collection: &str, file_path: Option<&str>) -> Self {
+        let writer = file_path.and_then(|path| {
+            let file = Path::new(path)
+                .parent()
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .map_or(Ok(()), create_dir_all)
+                .and_then(|_| File::create(path));
+            match file {
+                Ok(file) => Some(BufWriter::new(file)),
+                Err(e) => {
+                    println!("Error creating the rejects file {}: {}", path, e);
+                    None
+                }
+            }
+        });
+
+        Self {
+            collection: collection.to_string(),
+            writer,
+            counts: BTreeMap::new(),
+        }
+    }
+
+    pub fn reject(&mut self, doc: &Document, kind: RejectKind, reason: &str) {
+        *self.counts.entry(kind).or_default() += 1;
+        let Some(writer) = self.writer.as_mut() else {
+            return;
+        };
+        let line = json!({
+            "collection": self.collection,
+            "kind": kind.to_string(),
+            "reason": reason,
+            "page_content": doc.page_content,
+            "metadata": doc.metadata,
+        });
+        let written = writeln!(writer, "{}", line).and_then(|_| writer.flush());
+        if let Err(e) = written {
+            println!("Error writing the rejects file: {}", e);
+        }
+    }
+
+    pub fn total(&self) -> usize {
+        self.counts.values().sum()
+    }
+}
+
+impl fmt::Display for Rejects {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let counts = self
+            .counts
+            .iter()
+            .map(|(kind, count)| format!("{} {}", count, kind))
+            .collect::<Vec<String>>();
+        write!(f, "{} rejected ({})", self.total(), counts.join(", "))
+    }
+}
+
+// checks of each document before it is embedded. the near duplicates are found by the vector
+// space manager once the documents are embedded
+pub struct Validator {
+    config: Validation,
+    // duplicate key of the accepted documents, with the page content start of the first one
+    seen: HashMap<String, String>,
+    year: Regex,
+}
+
+impl Validator {
+    pub fn new(config: &Validation) -> Self {
+        Self {
+            config: config.clone(),
+            seen: HashMap::new(),
+            year: Regex::new(r"\d{4}").unwrap(),
+        }
+    }
+
+    pub fn check(&mut self, doc: &Document) -> Result<(), (RejectKind, String)> {
+        if let Some(field) = self
+            .config
+            .required
+            .iter()
+            .find(|f| normalize(doc.metadata.get(*f)).is_empty())
+        {
+            return Err((
+                RejectKind::MissingField,
+                format!("empty or missing field `{}`", field),
+            ));
+        }
+
+        let length = doc.page_content.trim().chars().count();
+        if length < self.config.min_length {
+            return Err((
+                RejectKind::TooShort,
+                format!(
+                    "content has {} characters, at least {} expected",
+                    length, self.config.min_length
+                ),
+            ));
+        }
+
+        if self.config.dedup_key.is_empty() {
+            return Ok(());
+        }
+        let key = self.dedup_key(doc);
+        match self.seen.get(&key) {
+            Some(first) => Err((
+                RejectKind::Duplicate,
+                format!("same `{}` as \"{}\"", key, first),
+            )),
+            None => {
+                let first = doc.page_content.chars().take(60).collect::<String>();
+                self.seen.insert(key, first);
+                Ok(())
+            }
+        }
+    }
+
+    // normalized values of the key fields, `field:year` keeps the year of a date
+    fn dedup_key(&self, doc: &Document) -> String {
+        self.config
+            .dedup_key
+            .iter()
+            .map(|spec| match spec.split_once(':') {
+                Some((field, "year")) => {
+                    let value = normalize(doc.metadata.get(field));
+                    self.year
+                        .find(&value)
+                        .map(|m| m.as_str().to_string())
+                        .unwrap_or(value)
+                }
+                _ => normalize(doc.metadata.get(spec)),
+            })
+            .collect::<Vec<String>>()
+            .join("|")
+    }
+}
+
+// lowercase words of the value without punctuation, list items joined by spaces
+fn normalize(value: Option<&Value>) -> String {
+    let text = match value {
+        None | Some(Value::Null) => String::default(),
+        Some(Value::String(s)) => s.to_string(),
+        Some(Value::Array(items)) => items
+            .iter()
+            .map(|v| normalize(Some(v)))
+            .collect::<Vec<String>>()
+            .join(" "),
+        Some(v) => v.to_string(),
+    };
+    text.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+// near duplicates among the embedded documents of a load, found with an lsh index so each
+// document is only compared with the accepted ones of its buckets
+pub struct NearDuplicates {
+    threshold: f64,
+    planes: OnceLock<Vec<Vec<f64>>>,
+    index: Mutex<LshIndex>,
+}
+
+#[derive(Default)]
+struct LshIndex {
+    // accepted vectors with the id of their record
+    vectors: Vec<(String, Vec<f64>)>,
+    // positions of the vectors by band key, for each band
+    buckets: Vec<HashMap<u64, Vec<usize>>>,
+}
+
+impl NearDuplicates {
+    pub fn new(threshold: f64) -> Self {
+        Self {
+            threshold,
+            planes: OnceLock::new(),
+            index: Mutex::new(LshIndex {
+                vectors: Vec::new(),
+                buckets: vec![HashMap::new(); BANDS],
+            }),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.threshold > 0.0
+    }
+
+    // record and similarity of the accepted vector the vector is a near duplicate of, the
+    // vector is accepted otherwise. the vectors of the same record are not compared
+    pub fn check(&self, record_id: &str, vector: &[f64]) -> Option<(String, f64)> {
+        let keys = self.band_keys(vector);
+
+        let mut index = self.index.lock().unwrap();
+        let mut candidates = keys
+            .iter()
+            .zip(index.buckets.iter())
+            .filter_map(|(key, bucket)| bucket.get(key))
+            .flatten()
+            .copied()
+            .collect::<Vec<usize>>();
+        candidates.sort_unstable();
+        candidates.dedup();
+        let duplicate = candidates
+            .into_iter()
+            .map(|position| &index.vectors[position])
+            .filter(|(other, _)| other != record_id)
+            .map(|(other, v)| (other.to_string(), cosine_similarity(vector, v)))
+            .find(|(_, similarity)| *similarity >= self.threshold);
+        if duplicate.is_some() {
+            return duplicate;
+        }
+        index.add(record_id, vector, keys);
+        None
+    }
+
+    // accept a vector without comparing it, e.g. one already in the store
+    pub fn insert(&self, record_id: &str, vector: &[f64]) {
+        let keys = self.band_keys(vector);
+        self.index.lock().unwrap().add(record_id, vector, keys);
+    }
+
+    // signs of the vector projections, packed by band
+    fn band_keys(&self, vector: &[f64]) -> Vec<u64> {
+        let planes = self.planes.get_or_init(|| random_planes(vector.len()));
+        planes
+            .chunks(BAND_BITS)
+            .map(|band| {
+                band.iter().fold(0u64, |key, plane| {
+                    let projection = plane.iter().zip(vector).map(|(p, x)| p * x).sum::<f64>();
+                    (key << 1) | (projection >= 0.0) as u64
+                })
+            })
+            .collect()
+    }
+}
+
+impl LshIndex {
+    fn add(&mut self, record_id: &str, vector: &[f64], keys: Vec<u64>) {
+        let position = self.vectors.len();
+        self.vectors.push((record_id.to_string(), vector.to_vec()));
+        for (key, bucket) in keys.into_iter().zip(self.buckets.iter_mut()) {
+            bucket.entry(key).or_default().push(position);
+        }
+    }
+}
+
+// gaussian hyperplanes from a fixed seed, so a load gives the same buckets on each run
+fn random_planes(dimensions: usize) -> Vec<Vec<f64>> {
+    let mut seed = 0x9e3779b97f4a7c15u64;
+    let mut uniform = move || {
+        seed = seed.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = seed;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^= z >> 31;
+        ((z >> 11) as f64 + 1.0) / (1u64 << 53) as f64
+    };
+    (0..BANDS * BAND_BITS)
+        .map(|_| {
+            (0..dimensions)
+                .map(|_| (-2.0 * uniform().ln()).sqrt() * (2.0 * PI * uniform()).cos())
+                .collect()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // deterministic vectors spread over the sphere
+    fn vector(i: usize, dimensions: usize) -> Vec<f64> {
+        let mut state = (i as u64 + 1).wrapping_mul(0x9e3779b97f4a7c15);
+        (0..dimensions)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state % 2000) as f64 / 1000.0 - 1.0
+            })
+            .collect()
+    }
+
+    #[test]
+    fn near_duplicates_are_found_through_the_index() {
+        let near_duplicates = NearDuplicates::new(0.97);
+        for i in 0..500 {
+            assert_eq!(
+                near_duplicates.check(&format!("doc{}", i), &vector(i, 64)),
+                None
+            );
+        }
+
+        // a slightly moved copy of an accepted vector
+        let mut copy = vector(42, 64);
+        copy[0] += 0.05;
+        let (other, similarity) = near_duplicates.check("copy", &copy).unwrap();
+        assert_eq!(other, "doc42");
+        assert!(similarity >= 0.97);
+
+        // the chunks of a record are not duplicates of each other
+        assert_eq!(near_duplicates.check("doc7", &vector(7, 64)), None);
+        assert_eq!(near_duplicates.index.lock().unwrap().vectors.len(), 501);
+    }
+}
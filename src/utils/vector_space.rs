@@ -1,15 +1,45 @@
 use langchain_rust::{
-    add_documents,
-    embedding::ollama::OllamaEmbedder,
+    embedding::{ollama::OllamaEmbedder, Embedder},
     schemas::Document,
-    similarity_search,
-    vectorstore::{
-        pgvector::{Store, StoreBuilder},
-        VectorStore,
-    },
 };
-use serde_json::{Result, Value};
-use std::{collections::HashMap, fs::OpenOptions, io::BufReader};
+use regex::Regex;
+use serde_json::{json, Map, Value};
+use thiserror::Error;
+
+use super::{
+    chunker::{parent_id, Chunker, CHUNK_COUNT_FIELD, CHUNK_INDEX_FIELD},
+    config_praser::{ColumnMapping, Domain, Embedding, FieldSchema, RecordSchema},
+    documents::FileDataLoader,
+    records::{read_records, RecordError, RecordResult, Records, SkipReport},
+    retriever::{RetrieverError, CONTENT_HASH_FIELD},
+    store::VectorStore,
+    validation::{NearDuplicates, RejectKind, Rejects, Validator},
+};
+use futures::{stream, StreamExt};
+use indicatif::{ProgressBar, ProgressStyle};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    fs::{create_dir_all, read_to_string, remove_file, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+#[derive(Debug, Error)]
+pub enum VectorSpaceError {
+    #[error("Embedding failed: {0}")]
+    Embedding(String),
+    #[error(transparent)]
+    Database(#[from] RetrieverError),
+    #[error("The embedding model gives {found} dimensions but {origin} has {expected}")]
+    DimensionMismatch {
+        expected: usize,
+        found: usize,
+        origin: String,
+    },
+}
 
 #[derive(Debug)]
 pub struct EmbeddingManager<'a> {
@@ -30,178 +60,919 @@ impl<'a> EmbeddingManager<'a> {
             .with_api_base(self.api_base_url.to_string())
             .with_model(self.model_name)
     }
+
+    // dimension of the model vectors, found by embedding a probe text
+    pub async fn probe_dimensions(&self) -> std::result::Result<usize, VectorSpaceError> {
+        let vector = self
+            .get_embeddings()
+            .embed_query("dimension probe")
+            .await
+            .map_err(|e| VectorSpaceError::Embedding(e.to_string()))?;
+        Ok(vector.len())
+    }
 }
 
-#[derive(Debug)]
 pub struct VectorSpaceManager<'a> {
     pub embedding_manager: EmbeddingManager<'a>,
-    pub db_url: String,
+    pub store: Arc<dyn VectorStore>,
     pub collection_name: String,
     pub pre_delete_collection: bool,
+    // documents embedded per request and number of requests in flight
+    pub batch_size: usize,
+    pub concurrency: usize,
+    // ids of the documents stored by an interrupted ingestion
+    pub checkpoint_path: Option<PathBuf>,
+    pub rejects: Option<Arc<Mutex<Rejects>>>,
+    // vectors of the stored documents and of the ones accepted by the current load, an embedded
+    // document too similar to one of them is rejected as a near duplicate
+    near_duplicates: NearDuplicates,
 }
 
 impl<'a> VectorSpaceManager<'a> {
     pub fn new(
         embedding_manager: EmbeddingManager<'a>,
-        db_url: String,
+        store: Arc<dyn VectorStore>,
         collection_name: String,
         pre_delete_collection: bool,
     ) -> Self {
         Self {
             embedding_manager,
-            db_url,
+            store,
             collection_name,
             pre_delete_collection,
+            batch_size: 64,
+            concurrency: 1,
+            checkpoint_path: None,
+            rejects: None,
+            near_duplicates: NearDuplicates::new(0.0),
         }
     }
 
-    pub async fn create_vector_space(&self, documents: Vec<Document>) -> Store {
-        let store = StoreBuilder::new()
-            .embedder(self.embedding_manager.get_embeddings())
-            .pre_delete_collection(self.pre_delete_collection)
-            .collection_name(&self.collection_name)
-            .connection_url(&self.db_url)
-            .vector_dimensions(2048)
-            .build()
-            .await
-            .unwrap();
+    pub fn with_batches(mut self, batch_size: usize, concurrency: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self.concurrency = concurrency.max(1);
+        self
+    }
 
-        let _ = add_documents!(store, &documents).await.map_err(|e| {
-            println!("Error adding documents: {:?}", e);
-        });
-        store
+    // the near duplicates are written to the rejects of the validation
+    pub fn with_rejects(
+        mut self,
+        rejects: Arc<Mutex<Rejects>>,
+        near_duplicate_threshold: f64,
+    ) -> Self {
+        self.rejects = Some(rejects);
+        self.near_duplicates = NearDuplicates::new(near_duplicate_threshold);
+        self
     }
-}
 
-pub trait DataLoader {
-    fn load_data(&self, json_file_path: String) -> Result<Value> {
-        let file = OpenOptions::new().read(true).open(json_file_path).unwrap();
-        let reader = BufReader::new(file);
-        let data: Value = serde_json::from_reader(reader)?;
-        // println!("{}", data);
+    // an existing checkpoint means the previous ingestion was interrupted: the collection is
+    // kept and the documents it lists are skipped
+    pub fn with_checkpoint(mut self, checkpoint_path: PathBuf) -> Self {
+        if checkpoint_path.is_file() {
+            println!(
+                "Resuming {} from {}",
+                self.collection_name,
+                checkpoint_path.display()
+            );
+            self.pre_delete_collection = false;
+        }
+        self.checkpoint_path = Some(checkpoint_path);
+        self
+    }
 
-        Ok(data)
+    fn checkpoint_ids(&self) -> HashSet<String> {
+        match &self.checkpoint_path {
+            Some(path) => read_to_string(path)
+                .map(|ids| ids.lines().map(|id| id.to_string()).collect())
+                .unwrap_or_default(),
+            None => HashSet::new(),
+        }
     }
 
-    fn create_documents(&self, json_file_path: String, length: Option<u32>) -> Vec<Document> {
-        let data: Value = self.load_data(json_file_path).unwrap();
-        let mut document: Vec<Document> = Vec::new();
-        let mut count = 0u32;
+    fn save_checkpoint(&self, ids: &[String]) -> std::io::Result<()> {
+        let Some(path) = &self.checkpoint_path else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        for id in ids {
+            writeln!(file, "{}", id)?;
+        }
+        Ok(())
+    }
+
+    // dimension of the embedding model, checked against the configured one, the embedding
+    // table and the collection when its embeddings are kept
+    pub async fn vector_dimensions(
+        &self,
+        configured: u16,
+    ) -> std::result::Result<usize, VectorSpaceError> {
+        let dimensions = self.embedding_manager.probe_dimensions().await?;
+        let mismatch = |expected: usize, origin: String| VectorSpaceError::DimensionMismatch {
+            expected,
+            found: dimensions,
+            origin,
+        };
+
+        if configured > 0 && configured as usize != dimensions {
+            return Err(mismatch(
+                configured as usize,
+                "`embedding.vector_dimensions`".to_string(),
+            ));
+        }
+
+        if let Some(expected) = self.store.table_dimensions().await? {
+            if expected != dimensions {
+                return Err(mismatch(expected, "the embedding table".to_string()));
+            }
+        }
+        if !self.pre_delete_collection {
+            if let Some(expected) = self
+                .store
+                .collection_dimensions(&self.collection_name)
+                .await?
+            {
+                if expected != dimensions {
+                    return Err(mismatch(
+                        expected,
+                        format!("collection `{}`", self.collection_name),
+                    ));
+                }
+            }
+        }
 
-        for item in data.as_array().unwrap() {
-            let obj = item.as_object().unwrap();
-            let mut meta_data: HashMap<String, Value> = HashMap::new();
+        Ok(dimensions)
+    }
+
+    // create the collection, emptied first with `pre_delete_collection`, and return its id
+    pub async fn create_vector_space(
+        &self,
+        dimensions: usize,
+    ) -> std::result::Result<String, VectorSpaceError> {
+        Ok(self
+            .store
+            .create_collection(
+                &self.collection_name,
+                dimensions,
+                self.pre_delete_collection,
+            )
+            .await?)
+    }
 
-            for (k, v) in obj.into_iter() {
-                meta_data.entry(k.to_string()).or_insert(v.clone());
+    // embed and upsert the new and changed documents only. a document id comes from its
+    // `id_field` value, or from its content when not set, so loading the same data twice
+    // changes nothing
+    pub async fn ingest_documents(
+        &self,
+        documents: impl Iterator<Item = Document>,
+        total: Option<u64>,
+        id_field: Option<&str>,
+        delete_missing: bool,
+    ) -> std::result::Result<IngestReport, VectorSpaceError> {
+        let collection_id = self
+            .store
+            .collection_id(&self.collection_name)
+            .await?
+            .ok_or(RetrieverError::MissingCollection(
+                self.collection_name.clone(),
+            ))?;
+        let existing = self.store.content_hashes(&collection_id).await?;
+        let checkpoint = self.checkpoint_ids();
+        self.index_stored_vectors(&collection_id).await?;
+
+        let progress = match total {
+            Some(total) => ProgressBar::new(total),
+            None => ProgressBar::new_spinner(),
+        };
+        progress.set_style(
+            ProgressStyle::with_template(
+                "{msg} [{elapsed_precise}] {wide_bar} {pos}/{len} ({per_sec}, eta {eta})",
+            )
+            .unwrap(),
+        );
+        progress.set_message(self.collection_name.clone());
+
+        let mut report = IngestReport::default();
+        let mut seen = HashSet::<String>::new();
+        // chunks of the previous version of a record that the current version no longer has
+        let mut stale = Vec::<String>::new();
+
+        // the documents are read lazily, the new and changed ones are grouped in batches that
+        // are embedded and stored concurrently. the checkpoint is appended after each batch
+        let changed = documents.filter_map(|mut doc| {
+            stale.extend(stale_chunk_ids(
+                &self.collection_name,
+                &doc,
+                id_field,
+                &existing,
+            ));
+            let hash = content_hash(&doc);
+            let id = document_id(&self.collection_name, &doc, id_field, &hash);
+            // a duplicated record is only counted once
+            if !seen.insert(id.clone()) {
+                progress.inc(1);
+                return None;
+            }
+            if checkpoint.contains(&id) {
+                report.unchanged += 1;
+                progress.inc(1);
+                return None;
+            }
+
+            let is_new = match existing.get(&id) {
+                Some(h) if *h == hash => {
+                    report.unchanged += 1;
+                    progress.inc(1);
+                    return None;
+                }
+                Some(_) => false,
+                None => true,
+            };
+            doc.metadata
+                .insert(CONTENT_HASH_FIELD.to_string(), Value::from(hash));
+            Some((id, doc, is_new))
+        });
+        let mut stored = stream::iter(changed)
+            .chunks(self.batch_size)
+            .map(|batch| self.store_batch(&collection_id, batch))
+            .buffer_unordered(self.concurrency);
+        let (mut added, mut updated, mut near_duplicates) = (0, 0, 0);
+        while let Some(batch) = stored.next().await {
+            let (ids, rejected) = batch?;
+            let new = ids.iter().filter(|(_, is_new)| *is_new).count();
+            added += new;
+            updated += ids.len() - new;
+            near_duplicates += rejected;
+
+            let ids = ids.into_iter().map(|(id, _)| id).collect::<Vec<String>>();
+            if let Err(e) = self.save_checkpoint(&ids) {
+                println!("Error writing the checkpoint: {}", e);
             }
+            progress.inc((ids.len() + rejected) as u64);
+        }
+        drop(stored);
+        progress.finish();
+        report.added = added;
+        report.updated = updated;
+        report.near_duplicates = near_duplicates;
+
+        // the stale chunks are removed even when the missing records are kept, their parent
+        // would be reassembled with them
+        let missing = if delete_missing {
+            existing
+                .keys()
+                .filter(|id| !seen.contains(*id))
+                .cloned()
+                .collect::<Vec<String>>()
+        } else {
+            stale
+        };
+        if !missing.is_empty() {
+            report.removed = self.store.delete_documents(&missing).await? as usize;
+        }
 
-            let item_content = self.get_page_content(item);
+        if let Some(path) = &self.checkpoint_path {
+            let _ = remove_file(path);
+        }
+        Ok(report)
+    }
 
-            let doc = Document::new(item_content).with_metadata(meta_data.clone());
-            document.push(doc);
+    // the stored documents are skipped before being embedded, their vectors are indexed first
+    // so the new documents are compared with them and a near duplicate rejected on a previous
+    // load is rejected again
+    async fn index_stored_vectors(
+        &self,
+        collection_id: &str,
+    ) -> std::result::Result<(), RetrieverError> {
+        if !self.near_duplicates.enabled() {
+            return Ok(());
+        }
+        for (id, doc, vector) in self.store.stored_vectors(collection_id).await? {
+            let record_id = parent_id(&doc).unwrap_or(id);
+            self.near_duplicates.insert(&record_id, &vector);
+        }
+        Ok(())
+    }
 
-            if length.is_some() {
-                if count == length.unwrap() {
-                    break;
+    // embed a batch and upsert it, returns the stored ids, whether they are new, and the number
+    // of near duplicates left out
+    async fn store_batch(
+        &self,
+        collection_id: &str,
+        batch: Vec<(String, Document, bool)>,
+    ) -> std::result::Result<(Vec<(String, bool)>, usize), VectorSpaceError> {
+        let texts = batch
+            .iter()
+            .map(|(_, d, _)| d.page_content.clone())
+            .collect::<Vec<String>>();
+        let vectors = self
+            .embedding_manager
+            .get_embeddings()
+            .embed_documents(&texts)
+            .await
+            .map_err(|e| VectorSpaceError::Embedding(e.to_string()))?;
+
+        let mut rows = Vec::<(String, Document, Vec<f64>)>::new();
+        let mut new = Vec::<bool>::new();
+        let mut rejected = 0;
+        for ((id, doc, is_new), vector) in batch.into_iter().zip(vectors) {
+            if let Some(reason) = self.near_duplicate(&id, &doc, &vector) {
+                if let Some(rejects) = &self.rejects {
+                    rejects
+                        .lock()
+                        .unwrap()
+                        .reject(&doc, RejectKind::NearDuplicate, &reason);
                 }
+                rejected += 1;
+                continue;
             }
+            rows.push((id, doc, vector));
+            new.push(is_new);
+        }
+        self.store.upsert_documents(collection_id, &rows).await?;
 
-            println!("########## current count : {count}");
+        let ids = rows.into_iter().map(|(id, _, _)| id).zip(new).collect();
+        Ok((ids, rejected))
+    }
 
-            count += 1;
+    // reason to reject the document when it is too similar to one accepted before. the chunks
+    // of a record are not compared with each other
+    fn near_duplicate(&self, id: &str, doc: &Document, vector: &[f64]) -> Option<String> {
+        if !self.near_duplicates.enabled() {
+            return None;
         }
+        let record_id = parent_id(doc).unwrap_or_else(|| id.to_string());
+        self.near_duplicates
+            .check(&record_id, vector)
+            .map(|(other, similarity)| {
+                format!("similarity {:.3} with document {}", similarity, other)
+            })
+    }
+}
 
-        document
+// counts of an ingestion
+#[derive(Debug, Default)]
+pub struct IngestReport {
+    pub added: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    pub removed: usize,
+    pub near_duplicates: usize,
+}
+
+impl fmt::Display for IngestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} added, {} updated, {} unchanged, {} removed, {} near duplicates",
+            self.added, self.updated, self.unchanged, self.removed, self.near_duplicates
+        )
     }
-    fn get_page_content(&self, item: &Value) -> String;
 }
 
-#[derive(Debug)]
-pub struct BookDataLoader;
+// 64 bit FNV-1a, stable across runs and platforms unlike the std hasher
+fn fnv1a(data: &str) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    for b in data.bytes() {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// hash of the page content and of the metadata, with sorted keys
+fn content_hash(doc: &Document) -> String {
+    let metadata = doc
+        .metadata
+        .iter()
+        .filter(|(k, _)| k.as_str() != CONTENT_HASH_FIELD)
+        .collect::<BTreeMap<&String, &Value>>();
+    format!(
+        "{:016x}",
+        fnv1a(&format!(
+            "{}\n{}",
+            doc.page_content,
+            serde_json::to_string(&metadata).unwrap_or_default()
+        ))
+    )
+}
+
+// ids are unique across the collections sharing the embedding table
+fn document_id(
+    collection_name: &str,
+    doc: &Document,
+    id_field: Option<&str>,
+    hash: &str,
+) -> String {
+    let mut key = match id_field.and_then(|f| doc.metadata.get(f)) {
+        Some(value) => id_value(value),
+        None => hash.to_string(),
+    };
+    // the chunks of a record share its id field
+    if let Some(index) = doc.metadata.get(CHUNK_INDEX_FIELD) {
+        key = format!("{}#{}", key, index);
+    }
+    key_id(collection_name, &key)
+}
+
+fn id_value(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.to_string(),
+        v => v.to_string(),
+    }
+}
+
+fn key_id(collection_name: &str, key: &str) -> String {
+    format!(
+        "{}-{:016x}",
+        collection_name,
+        fnv1a(&format!("{}:{}", collection_name, key))
+    )
+}
+
+// stored ids of the record that its current version does not replace: the chunks past its
+// chunk count, and the whole record when it is now chunked. checked on the first document of
+// the record. the records without `id_field` get new ids, and a new parent id, on each change
+fn stale_chunk_ids(
+    collection_name: &str,
+    doc: &Document,
+    id_field: Option<&str>,
+    existing: &HashMap<String, String>,
+) -> Vec<String> {
+    let Some(key) = id_field.and_then(|f| doc.metadata.get(f)).map(id_value) else {
+        return Vec::new();
+    };
+    let mut stale = Vec::<String>::new();
+    let first_chunk = match doc.metadata.get(CHUNK_INDEX_FIELD) {
+        None => 0,
+        Some(index) if index.as_u64() == Some(0) => {
+            let whole = key_id(collection_name, &key);
+            if existing.contains_key(&whole) {
+                stale.push(whole);
+            }
+            doc.metadata
+                .get(CHUNK_COUNT_FIELD)
+                .and_then(|c| c.as_u64())
+                .unwrap_or(1)
+        }
+        Some(_) => return stale,
+    };
+
+    // the chunks of a version are numbered from 0, the first missing one ends them
+    for index in first_chunk.. {
+        let id = key_id(collection_name, &format!("{}#{}", key, index));
+        if !existing.contains_key(&id) {
+            break;
+        }
+        stale.push(id);
+    }
+    stale
+}
 
+pub trait DataLoader {
+    // records of the data file, read one at a time, with the columns renamed by the mapping
+    fn records(&self, file_path: &str, mapping: &ColumnMapping) -> RecordResult<Records> {
+        read_records(file_path, mapping)
+    }
+
+    // documents built lazily from the records, at most `length` of them. the records that
+    // cannot be read or built are added to the skip report
+    fn create_documents<'a>(
+        &'a self,
+        file_path: &str,
+        length: Option<u32>,
+        mapping: &ColumnMapping,
+        skipped: &'a RefCell<SkipReport>,
+    ) -> RecordResult<Box<dyn Iterator<Item = Document> + 'a>> {
+        let documents = self
+            .records(file_path, mapping)?
+            .enumerate()
+            .filter_map(move |(position, record)| {
+                match record.and_then(|item| self.create_document(&item)) {
+                    Ok(doc) => Some(doc),
+                    Err(e) => {
+                        skipped.borrow_mut().skip(position, &e);
+                        None
+                    }
+                }
+            })
+            .take(length.map(|l| l as usize).unwrap_or(usize::MAX));
+        Ok(Box::new(documents))
+    }
+
+    // the record fields are kept as metadata
+    fn create_document(&self, item: &Value) -> RecordResult<Document> {
+        let meta_data = item
+            .as_object()
+            .ok_or(RecordError::NotAnObject)?
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect::<HashMap<String, Value>>();
+
+        Ok(Document::new(self.get_page_content(item)?).with_metadata(meta_data))
+    }
+
+    // number of records of the data file, for the progress bar
+    fn count_records(&self, file_path: &str, mapping: &ColumnMapping) -> RecordResult<u64> {
+        Ok(self
+            .records(file_path, mapping)?
+            .filter_map(|r| r.ok())
+            .count() as u64)
+    }
+
+    fn get_page_content(&self, item: &Value) -> RecordResult<String>;
+}
+
+// loader driven by the `schema` of a domain: the page content is rendered from a template, the
+// missing fields take their default and the records missing a required field are skipped
 #[derive(Debug)]
-pub struct MovieDataLoder;
+pub struct SchemaDataLoader {
+    schema: RecordSchema,
+    placeholder: Regex,
+}
 
-impl DataLoader for BookDataLoader {
-    fn get_page_content(&self, item: &Value) -> String {
-        let obj = item.as_object().unwrap();
+impl SchemaDataLoader {
+    pub fn new(schema: RecordSchema) -> Self {
+        Self {
+            schema,
+            placeholder: Regex::new(r"\{([A-Za-z0-9_.-]+)\}").unwrap(),
+        }
+    }
 
-        let genres = obj["genres"]
-            .as_array()
-            .unwrap()
-            .into_iter()
-            .map(|v| v.to_string())
-            .collect::<Vec<String>>();
+    // the record with the defaults of its empty fields
+    fn complete(&self, item: &Value) -> RecordResult<Map<String, Value>> {
+        let mut obj = item.as_object().ok_or(RecordError::NotAnObject)?.clone();
+        for field in self.schema.fields.iter() {
+            if !is_empty(obj.get(&field.name)) {
+                continue;
+            }
+            match &field.default {
+                Some(default) => {
+                    obj.insert(field.name.clone(), default.clone());
+                }
+                None if field.required => {
+                    return Err(RecordError::MissingField(field.name.clone()));
+                }
+                None => {}
+            }
+        }
+        Ok(obj)
+    }
 
-        format!(
-            r#"{} {} {} {} {}"#,
-            obj["title"].as_str().unwrap(),
-            obj["author"].as_str().unwrap(),
-            obj["publication_date"].as_str().unwrap(),
-            obj["description"].as_str().unwrap(),
-            genres.join(" ")
-        )
+    fn render(&self, obj: &Map<String, Value>) -> String {
+        self.placeholder
+            .replace_all(&self.schema.template, |caps: &regex::Captures| {
+                obj.get(&caps[1])
+                    .map(|v| field_text(v, &self.schema.list_separator))
+                    .unwrap_or_default()
+            })
+            .trim()
+            .to_string()
     }
 }
 
-impl DataLoader for MovieDataLoder {
-    fn get_page_content(&self, item: &Value) -> String {
-        let obj = item.as_object().unwrap();
-        let movie_genres_list = obj["movie_genres_list"]
-            .as_array()
-            .unwrap()
-            .into_iter()
-            .map(|v| v.to_string())
-            .collect::<Vec<String>>();
+impl DataLoader for SchemaDataLoader {
+    fn create_document(&self, item: &Value) -> RecordResult<Document> {
+        let obj = self.complete(item)?;
+        let meta_data = obj
+            .iter()
+            .filter(|(k, _)| match &self.schema.metadata {
+                Some(fields) => fields.contains(k),
+                None => true,
+            })
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect::<HashMap<String, Value>>();
+
+        Ok(Document::new(self.render(&obj)).with_metadata(meta_data))
+    }
 
-        let movie_actor_list = obj["movie_actor_list"]
-            .as_array()
-            .unwrap()
-            .into_iter()
-            .map(|v| v.to_string())
-            .collect::<Vec<String>>();
+    fn get_page_content(&self, item: &Value) -> RecordResult<String> {
+        Ok(self.render(&self.complete(item)?))
+    }
+}
 
-        format!(
-            r#"{} {} {} {} {}"#,
-            obj["title"].as_str().unwrap(),
-            obj["release_date"].as_str().unwrap(),
-            obj["summary"].as_str().unwrap(),
-            movie_genres_list.join(" "),
-            movie_actor_list.join(" ")
-        )
+fn is_empty(value: Option<&Value>) -> bool {
+    match value {
+        None | Some(Value::Null) => true,
+        Some(Value::String(s)) => s.trim().is_empty(),
+        Some(Value::Array(items)) => items.is_empty(),
+        Some(_) => false,
+    }
+}
+
+// text of a field in the page content, list items are joined with the separator
+fn field_text(value: &Value, separator: &str) -> String {
+    match value {
+        Value::Null => String::default(),
+        Value::String(s) => s.to_string(),
+        Value::Array(items) => items
+            .iter()
+            .map(|v| field_text(v, separator))
+            .filter(|t| !t.is_empty())
+            .collect::<Vec<String>>()
+            .join(separator),
+        v => v.to_string(),
     }
 }
 
-pub async fn process_data<T: DataLoader>(
-    json_file_path: String,
+fn field(name: &str, required: bool) -> FieldSchema {
+    FieldSchema {
+        name: name.to_string(),
+        required,
+        default: None,
+    }
+}
+
+// schema of the "book" loader
+pub fn book_schema() -> RecordSchema {
+    RecordSchema {
+        template: "{title} {author} {publication_date} {description} {genres}".to_string(),
+        fields: vec![
+            field("title", true),
+            field("author", false),
+            field("publication_date", false),
+            field("description", false),
+            field("genres", false),
+        ],
+        metadata: None,
+        list_separator: " ".to_string(),
+    }
+}
+
+// schema of the "movie" loader
+pub fn movie_schema() -> RecordSchema {
+    RecordSchema {
+        template: "{title} {release_date} {summary} {movie_genres_list} {movie_actor_list}"
+            .to_string(),
+        fields: vec![
+            field("title", true),
+            field("release_date", false),
+            field("summary", false),
+            field("movie_genres_list", false),
+            field("movie_actor_list", false),
+        ],
+        metadata: None,
+        list_separator: " ".to_string(),
+    }
+}
+
+// data loader of a domain, from the name of its `loader` field. "schema" uses the `schema` of
+// the domain and "documents" reads a directory of files
+pub fn data_loader(domain: &Domain) -> Option<Box<dyn DataLoader>> {
+    let schema = match domain.loader.as_str() {
+        "book" => book_schema(),
+        "movie" => movie_schema(),
+        "schema" => domain.schema.clone()?,
+        "documents" => return Some(Box::new(FileDataLoader)),
+        _ => return None,
+    };
+    Some(Box::new(SchemaDataLoader::new(schema)))
+}
+
+pub async fn process_data(
+    domain: &Domain,
     model_name: &str,
-    db_url: String,
-    collection_name: String,
-    pre_delete_collection: bool,
-    data_loader_class: T,
-    length: Option<u32>,
+    store: Arc<dyn VectorStore>,
+    embedding: &Embedding,
+    data_loader_class: &dyn DataLoader,
     embedder_url: &str,
 ) {
-    let embedding_manager = EmbeddingManager::new(&model_name, embedder_url.to_string());
+    let embedding_manager = EmbeddingManager::new(model_name, embedder_url.to_string());
 
     // Initialize the vector space manager with the embedding manager
     let vector_space_manager = VectorSpaceManager::new(
         embedding_manager,
-        db_url,
-        collection_name,
-        pre_delete_collection,
-    );
+        store.clone(),
+        domain.collection.clone(),
+        embedding.pre_delete_embeddings,
+    )
+    .with_batches(embedding.batch_size, embedding.concurrency);
+    // a store saved once the load is done has nothing to resume from
+    let vector_space_manager = if store.durable_writes() {
+        vector_space_manager.with_checkpoint(
+            Path::new(&embedding.checkpoint_dir).join(format!("{}.checkpoint", domain.collection)),
+        )
+    } else {
+        vector_space_manager
+    };
+    let rejects = Arc::new(Mutex::new(Rejects::new(
+        &domain.collection,
+        domain.validation.rejects_path.as_deref(),
+    )));
+    let vector_space_manager = vector_space_manager
+        .with_rejects(rejects.clone(), domain.validation.near_duplicate_threshold);
+
+    let dimensions = match vector_space_manager
+        .vector_dimensions(embedding.vector_dimensions)
+        .await
+    {
+        Ok(dimensions) => dimensions,
+        Err(e) => {
+            println!(
+                "Skipping collection {}: {}",
+                vector_space_manager.collection_name, e
+            );
+            return;
+        }
+    };
 
     let data_loader = data_loader_class;
-    let documents = data_loader.create_documents(json_file_path, length);
+    let total = data_loader
+        .count_records(&domain.data_path, &domain.mapping)
+        .ok()
+        .map(|count| match domain.number_of_data {
+            Some(length) => count.min(length as u64),
+            None => count,
+        });
+    let skipped = RefCell::new(SkipReport::default());
+    let documents = match data_loader.create_documents(
+        &domain.data_path,
+        domain.number_of_data,
+        &domain.mapping,
+        &skipped,
+    ) {
+        Ok(documents) => documents,
+        Err(e) => {
+            println!("Error reading {}: {}", domain.data_path, e);
+            return;
+        }
+    };
+
+    // documents missing a required field, too short or duplicated are rejected before embedding
+    let mut validator = Validator::new(&domain.validation);
+    let documents = documents.filter(|doc| match validator.check(doc) {
+        Ok(()) => true,
+        Err((kind, reason)) => {
+            rejects.lock().unwrap().reject(doc, kind, &reason);
+            false
+        }
+    });
+
+    // long documents are split in chunks pointing to the id of their record
+    let chunker = Chunker::new(&domain.chunking);
+    // the progress counts chunks, their number is not known beforehand
+    let total = total.filter(|_| !chunker.enabled());
+    let documents: Box<dyn Iterator<Item = Document>> = if chunker.enabled() {
+        let collection_name = vector_space_manager.collection_name.clone();
+        let id_field = domain.id_field.clone();
+        Box::new(documents.flat_map(move |doc| {
+            let parent_id = document_id(
+                &collection_name,
+                &doc,
+                id_field.as_deref(),
+                &content_hash(&doc),
+            );
+            chunker.split_document(doc, &parent_id)
+        }))
+    } else {
+        Box::new(documents)
+    };
 
     //Create and save the vector space in db
-    let vector_store = vector_space_manager.create_vector_space(documents).await;
+    if let Err(e) = vector_space_manager.create_vector_space(dimensions).await {
+        println!("Error creating collection {}: {}", domain.collection, e);
+        return;
+    }
+    match vector_space_manager
+        .ingest_documents(
+            documents,
+            total,
+            domain.id_field.as_deref(),
+            embedding.delete_missing,
+        )
+        .await
+    {
+        Ok(report) => println!("{}: {}", vector_space_manager.collection_name, report),
+        Err(e) => println!("Error adding documents: {}", e),
+    }
+    let skipped = skipped.into_inner();
+    if skipped.skipped > 0 {
+        println!("{}: {}", vector_space_manager.collection_name, skipped);
+    }
+    {
+        let rejects = rejects.lock().unwrap();
+        if rejects.total() > 0 {
+            println!("{}: {}", vector_space_manager.collection_name, rejects);
+        }
+    }
+
+    // dimension of the collection checked at query time, then the search indexes are built
+    // and the store saved
+    let loaded = match store
+        .set_collection_metadata(
+            &vector_space_manager.collection_name,
+            json!({
+                "vector_dimensions": dimensions,
+                "embedding_model": model_name,
+            }),
+        )
+        .await
+    {
+        Ok(_) => store.finish_load().await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = loaded {
+        println!("Error indexing the collection: {}", e);
+    }
+}
 
-    // perform a search for testing
-    let query = "Baby Boy";
-    let search_results = similarity_search!(vector_store, query, 5).await.unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{
+        chunker::{reassemble, ChunkStrategy},
+        config_praser::Chunking,
+        memory_store::InMemoryStore,
+    };
+
+    fn record(text: &str) -> Document {
+        let metadata = HashMap::from([("isbn".to_string(), Value::from("978-0441013593"))]);
+        Document::new(text).with_metadata(metadata)
+    }
+
+    // the chunks of the record with their id, as the ingestion stores them
+    fn chunks(text: &str) -> Vec<(String, Document, Vec<f64>)> {
+        let chunker = Chunker::new(&Chunking {
+            strategy: ChunkStrategy::Sentence,
+            size: 25,
+            overlap: 0,
+        });
+        let doc = record(text);
+        let parent = document_id("books", &doc, Some("isbn"), &content_hash(&doc));
+        chunker
+            .split_document(doc, &parent)
+            .into_iter()
+            .map(|chunk| {
+                let id = document_id("books", &chunk, Some("isbn"), &content_hash(&chunk));
+                (id, chunk, vec![1.0, 0.0])
+            })
+            .collect()
+    }
 
-    println!("{:?}", search_results);
+    #[tokio::test]
+    async fn shrinking_record_leaves_no_stale_chunks() {
+        let store = InMemoryStore::open("unused.json", None).unwrap();
+        store.create_collection("books", 2, false).await.unwrap();
+        let old = chunks("First sentence here. Second sentence here. Third sentence here.");
+        store.upsert_documents("books", &old).await.unwrap();
+
+        let new = chunks("First sentence here. Second one.");
+        assert_eq!((old.len(), new.len()), (3, 2));
+        let existing = store.content_hashes("books").await.unwrap();
+        let stale = stale_chunk_ids("books", &new[0].1, Some("isbn"), &existing);
+        assert_eq!(stale, vec![old[2].0.clone()]);
+        // only the first chunk of a record looks for stale chunks
+        assert!(stale_chunk_ids("books", &new[1].1, Some("isbn"), &existing).is_empty());
+
+        store.upsert_documents("books", &new).await.unwrap();
+        store.delete_documents(&stale).await.unwrap();
+        let parent = parent_id(&new[0].1).unwrap();
+        let stored = store.chunks_of("books", &[parent]).await.unwrap();
+        let parents = reassemble(vec![new[0].1.clone()], stored);
+        assert_eq!(parents[0].page_content, "First sentence here. Second one.");
+
+        // the record now fits in one document, every chunk is stale
+        let existing = store.content_hashes("books").await.unwrap();
+        let whole = record("Short.");
+        let stale = stale_chunk_ids("books", &whole, Some("isbn"), &existing);
+        assert_eq!(stale, vec![new[0].0.clone(), new[1].0.clone()]);
+
+        // and the whole record is stale once it is chunked again
+        let whole_id = document_id("books", &whole, Some("isbn"), "");
+        let existing = HashMap::from([(whole_id.clone(), String::new())]);
+        assert_eq!(
+            stale_chunk_ids("books", &new[0].1, Some("isbn"), &existing),
+            vec![whole_id]
+        );
+    }
+
+    #[tokio::test]
+    async fn stored_documents_are_compared_with_new_ones() {
+        let store = Arc::new(InMemoryStore::open("unused.json", None).unwrap());
+        store.create_collection("books", 2, false).await.unwrap();
+        let stored = vec![("a".to_string(), record("Dune"), vec![1.0, 0.0])];
+        store.upsert_documents("books", &stored).await.unwrap();
+
+        let manager = VectorSpaceManager::new(
+            EmbeddingManager::new("unused", String::new()),
+            store,
+            "books".to_string(),
+            false,
+        )
+        .with_rejects(Arc::new(Mutex::new(Rejects::new("books", None))), 0.95);
+        manager.index_stored_vectors("books").await.unwrap();
+
+        // a record rejected on a previous load is rejected again, an update of a stored record
+        // is not compared with its previous version
+        let reason = manager.near_duplicate("b", &record("Dune!"), &[0.99, 0.01]);
+        assert!(reason.unwrap().ends_with("with document a"));
+        assert!(manager
+            .near_duplicate("a", &record("Dune"), &[0.99, 0.01])
+            .is_none());
+        assert!(manager
+            .near_duplicate("c", &record("Emma"), &[0.0, 1.0])
+            .is_none());
+    }
 }